@@ -0,0 +1,39 @@
+// Each bench target uses a different subset of the fixtures
+#![allow(dead_code)]
+
+use image::{DynamicImage, ImageOutputFormat, RgbImage, RgbaImage};
+
+// Deterministic stand-ins for real pictures: smooth gradients with some
+// high frequency detail, so encoders and k-means do realistic work.
+pub fn photo(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([
+            (x * 255 / width) as u8,
+            (y * 255 / height) as u8,
+            ((x ^ y) % 256) as u8,
+        ])
+    }))
+}
+
+pub fn encode(img: &DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+    let mut data = Vec::new();
+    img.write_to(&mut data, format).unwrap();
+    data
+}
+
+pub fn sizes() -> Vec<(u32, u32)> {
+    vec![(320, 240), (1920, 1080), (4000, 3000)]
+}
+
+pub fn formats() -> Vec<(&'static str, ImageOutputFormat)> {
+    vec![
+        ("jpeg", ImageOutputFormat::Jpeg(85)),
+        ("png", ImageOutputFormat::Png),
+        ("gif", ImageOutputFormat::Gif),
+        ("bmp", ImageOutputFormat::Bmp),
+    ]
+}
+
+pub fn thumbnail(size: u32) -> RgbaImage {
+    photo(size, size).to_rgba8()
+}
@@ -0,0 +1,68 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use image::ImageOutputFormat;
+use search_api::actors::dominant_color::get_image_pixels;
+use search_api::sampling::SamplingOptions;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod fixtures;
+
+// Tracks the heap in use and its peak, to compare the memory of both decoders
+struct PeakAllocator;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(current, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+fn peak_bytes(data: &[u8], options: &SamplingOptions) -> usize {
+    let before = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    get_image_pixels(data, options).unwrap();
+    PEAK.load(Ordering::SeqCst) - before
+}
+
+fn jpeg_decode(c: &mut Criterion) {
+    let full = SamplingOptions {
+        fast_jpeg: false,
+        ..SamplingOptions::default()
+    };
+    let fast = SamplingOptions::default();
+
+    for (width, height) in fixtures::sizes() {
+        let data = fixtures::encode(&fixtures::photo(width, height), ImageOutputFormat::Jpeg(85));
+        println!(
+            "{}x{} peak memory: full {} bytes, scaled {} bytes",
+            width,
+            height,
+            peak_bytes(&data, &full),
+            peak_bytes(&data, &fast)
+        );
+
+        let mut group = c.benchmark_group(format!("jpeg {}x{}", width, height));
+        group.sample_size(20);
+        group.bench_function("full", |b| b.iter(|| get_image_pixels(&data, &full)));
+        group.bench_function("scaled", |b| b.iter(|| get_image_pixels(&data, &fast)));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, jpeg_decode);
+criterion_main!(benches);
@@ -0,0 +1,80 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::DynamicImage;
+use search_api::actors::dominant_color::get_dominant_colors;
+use search_api::colors::{lab_distance, rgb8_to_lab, target_distance, Target};
+use search_api::sampling::{sample_pixels, SamplingOptions};
+
+mod fixtures;
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    group.sample_size(20);
+    for (width, height) in fixtures::sizes() {
+        let img = fixtures::photo(width, height);
+        for (name, format) in fixtures::formats() {
+            let data = fixtures::encode(&img, format);
+            let format = image::guess_format(&data).unwrap();
+            group.bench_with_input(
+                BenchmarkId::new(name, format!("{}x{}", width, height)),
+                &data,
+                |b, data| b.iter(|| image::load_from_memory_with_format(data, format).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn resize(c: &mut Criterion) {
+    let options = SamplingOptions::default();
+    let mut group = c.benchmark_group("resize");
+    for (width, height) in fixtures::sizes() {
+        let img = fixtures::photo(width, height);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}x{}", width, height)),
+            &img,
+            |b, img: &DynamicImage| {
+                b.iter(|| {
+                    img.resize(options.size, options.size, options.filter)
+                        .to_rgba8()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn lab_conversion(c: &mut Criterion) {
+    let options = SamplingOptions::default();
+    let thumbnail = fixtures::thumbnail(options.size);
+    c.bench_function("lab conversion", |b| {
+        b.iter(|| sample_pixels(&thumbnail, &options))
+    });
+}
+
+fn clustering(c: &mut Criterion) {
+    let options = SamplingOptions::default();
+    let sample = sample_pixels(&fixtures::thumbnail(options.size), &options);
+    c.bench_function("clustering", |b| b.iter(|| get_dominant_colors(&sample)));
+}
+
+fn scoring(c: &mut Criterion) {
+    let options = SamplingOptions::default();
+    let sample = sample_pixels(&fixtures::thumbnail(options.size), &options);
+    let colors = get_dominant_colors(&sample);
+    let palette = Target::Palette(colors.palette.clone());
+    let layout = Target::Layout(colors.layout.clone());
+    let (a, b) = (rgb8_to_lab(200, 30, 30), rgb8_to_lab(30, 30, 200));
+
+    c.bench_function("lab distance", |bencher| {
+        bencher.iter(|| lab_distance(&a, &b))
+    });
+    c.bench_function("palette distance", |b| {
+        b.iter(|| target_distance(&palette, &colors))
+    });
+    c.bench_function("layout distance", |b| {
+        b.iter(|| target_distance(&layout, &colors))
+    });
+}
+
+criterion_group!(benches, decode, resize, lab_conversion, clustering, scoring);
+criterion_main!(benches);
@@ -0,0 +1,97 @@
+use async_channel::{Receiver, Sender};
+use log::debug;
+use std::io;
+
+use crate::color_index::{append_line, get_line, ColorIndex, IndexEntry, NearestQuery};
+use crate::loggable::Loggable;
+
+type OneSender<T> = oneshot::Sender<T>;
+
+pub enum ColorIndexMessage {
+    Insert(IndexEntry),
+    Nearest(NearestQuery, OneSender<Vec<(f32, IndexEntry)>>),
+}
+
+// The line is written on the blocking pool, so the file never blocks the
+// runtime. Messages still wait for it, entries are appended in order.
+async fn insert(index: &mut ColorIndex, entry: IndexEntry) -> io::Result<bool> {
+    if !index.accepts(&entry) {
+        return Ok(false);
+    }
+    if let Some(file) = index.file() {
+        let (file, line) = (file.to_owned(), get_line(&entry)?);
+        tokio::task::spawn_blocking(move || append_line(&file, &line))
+            .await
+            .map_err(|x| io::Error::new(io::ErrorKind::Other, x))??;
+    }
+    Ok(index.insert_in_memory(entry))
+}
+
+async fn color_index(r: Receiver<ColorIndexMessage>, mut index: ColorIndex) {
+    while let Ok(msg) = r.recv().await {
+        match msg {
+            ColorIndexMessage::Insert(entry) => {
+                let id = entry.candidate.id.clone();
+                if let Ok(true) = insert(&mut index, entry).await.log_if_error() {
+                    debug!(target: "color_index", "indexed {}, {} entries", id, index.len());
+                }
+            }
+            ColorIndexMessage::Nearest(query, reply) => {
+                let nearest = index
+                    .nearest(&query)
+                    .into_iter()
+                    .map(|(distance, entry)| (distance, entry.clone()))
+                    .collect();
+                let _ = reply.send(nearest);
+            }
+        }
+    }
+}
+
+pub fn spawn_color_index(index: ColorIndex) -> Sender<ColorIndexMessage> {
+    let (w, r) = async_channel::unbounded::<ColorIndexMessage>();
+    tokio::spawn(color_index(r, index));
+    w
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::colors::{ImageColors, PaletteColor};
+    use crate::image_source::Candidate;
+    use palette::Lab;
+
+    fn entry(id: &str) -> IndexEntry {
+        let candidate = Candidate {
+            id: id.to_owned(),
+            url: format!("https://i.imgur.com/{}.png", id),
+            metadata: serde_json::Map::new(),
+        };
+        let color = Lab::new(50.0, 0.0, 0.0);
+        let colors = ImageColors {
+            palette: vec![PaletteColor { color, weight: 1.0 }],
+            layout: vec![Some(color)],
+            final_url: None,
+        };
+        IndexEntry::new("reddit", candidate, &colors)
+    }
+
+    #[tokio::test]
+    async fn inserts_are_written_in_order() {
+        let file = std::env::temp_dir().join(format!(
+            "search-api-index-actor-{}/index.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        let mut index = ColorIndex::open(file.clone()).unwrap();
+        for id in ["a", "b", "a", "c"].iter() {
+            insert(&mut index, entry(id)).await.unwrap();
+        }
+        assert_eq!(index.len(), 3);
+        let index = ColorIndex::open(file.clone()).unwrap();
+        assert_eq!(index.len(), 3);
+        let lines = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+        let _ = std::fs::remove_dir_all(file.parent().unwrap());
+    }
+}
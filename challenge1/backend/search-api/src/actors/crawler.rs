@@ -0,0 +1,514 @@
+use async_channel::{Receiver, Sender};
+use log::{debug, info};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::actors::color_index::ColorIndexMessage;
+use crate::actors::dominant_color::DominantColorDistanceMessage;
+use crate::actors::dominant_color_cache::DominantColorCacheMessage;
+use crate::color_index::IndexEntry;
+use crate::colors::{ImageColors, Target};
+use crate::image_source::{self, ImageSource, Listing, Sort, TimeRange};
+use crate::search::SearchActors;
+
+type OneSender<T> = oneshot::Sender<T>;
+
+#[derive(Debug, Error)]
+pub enum ErrorCode {
+    #[error("cannot send to cache")]
+    CannotSendToCache,
+    #[error("cannot wait cache")]
+    CannotWaitCache,
+    #[error("cannot send to index")]
+    CannotSendToIndex,
+    #[error("{0}")]
+    Source(#[from] image_source::ErrorCode),
+}
+
+#[derive(Clone, Debug)]
+pub struct CrawlerOptions {
+    // queries crawled every round, like "ferrari" or "red car"
+    pub queries: Vec<String>,
+    // subreddits whose new and top posts are crawled every round, like
+    // "EarthPorn"
+    pub subreddits: Vec<String>,
+    // the default source when None
+    pub source: Option<String>,
+    // between the end of a round and the start of the next one
+    pub interval: Duration,
+    // candidates of each query per round, the same a search ranks
+    pub max_candidates: u64,
+}
+
+impl Default for CrawlerOptions {
+    fn default() -> CrawlerOptions {
+        CrawlerOptions {
+            queries: vec![],
+            subreddits: vec![],
+            source: None,
+            interval: Duration::from_secs(60 * 60),
+            max_candidates: 300,
+        }
+    }
+}
+
+// Counters are of the current round while the query is crawled, of the last
+// one afterwards. Times are unix seconds.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct QueryStatus {
+    pub query: String,
+    pub candidates: u64,
+    // colors read from the cache
+    pub cached: u64,
+    // colors extracted now
+    pub analyzed: u64,
+    pub failed: u64,
+    pub last_started: Option<u64>,
+    pub last_finished: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CrawlerStatus {
+    pub source: String,
+    pub interval_ms: u64,
+    pub rounds: u64,
+    // query being crawled
+    pub running: Option<String>,
+    // when the next round starts, None while a round runs
+    pub next_round: Option<u64>,
+    pub queries: Vec<QueryStatus>,
+}
+
+pub enum CrawlerMessage {
+    Status(OneSender<CrawlerStatus>),
+    // sent by the crawler as it makes progress
+    Update(CrawlerStatus),
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+async fn send_update(status_actor: &Sender<CrawlerMessage>, status: &CrawlerStatus) {
+    let _ = status_actor
+        .send(CrawlerMessage::Update(status.clone()))
+        .await;
+}
+
+// Colors from the cache, whether they were there, or from the dominant color
+// actor, written to the cache. None when the image cannot be analyzed.
+async fn get_colors(
+    actors: &SearchActors,
+    url: &str,
+) -> Result<Option<(ImageColors, bool)>, ErrorCode> {
+    let (w, s) = oneshot::channel();
+    actors
+        .cache
+        .send(DominantColorCacheMessage::Read(url.to_owned(), w))
+        .await
+        .or(Err(ErrorCode::CannotSendToCache))?;
+    // the index answers layout targets too, so entries cached without a
+    // layout are analyzed again
+    let cached = s.await.or(Err(ErrorCode::CannotWaitCache))?;
+    if let Some(colors) = cached.filter(|x| x.has_layout()) {
+        return Ok(Some((colors, true)));
+    }
+
+    // the distance to an empty palette is free, only the colors are needed
+    let (w, s) = oneshot::channel();
+    let msg =
+        DominantColorDistanceMessage(url.to_owned(), Target::Palette(vec![]), w, Box::new(|_| {}));
+    if actors.dominant_color.send(msg).await.is_err() {
+        return Ok(None);
+    }
+    match s.await {
+        Ok(Some((colors, _))) => {
+            actors
+                .cache
+                .send(DominantColorCacheMessage::Write(
+                    url.to_owned(),
+                    colors.clone(),
+                ))
+                .await
+                .or(Err(ErrorCode::CannotSendToCache))?;
+            Ok(Some((colors, false)))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Listings of each crawled subreddit, recent posts and the best of the week
+const SUBREDDIT_LISTINGS: [Listing; 2] = [
+    Listing {
+        sort: Sort::New,
+        time: TimeRange::All,
+    },
+    Listing {
+        sort: Sort::Top,
+        time: TimeRange::Week,
+    },
+];
+
+// What is paged every round, in order
+#[derive(Clone, Debug)]
+enum CrawlJob {
+    Query(String),
+    Subreddit(String, Listing),
+}
+
+impl CrawlJob {
+    // shown as the query of its status
+    fn name(&self) -> String {
+        match self {
+            CrawlJob::Query(query) => query.clone(),
+            CrawlJob::Subreddit(subreddit, listing) => {
+                format!("r/{}/{}", subreddit, listing.sort.as_str())
+            }
+        }
+    }
+}
+
+fn get_jobs(options: &CrawlerOptions) -> Vec<CrawlJob> {
+    let queries = options.queries.iter().map(|x| CrawlJob::Query(x.clone()));
+    let subreddits = options.subreddits.iter().flat_map(|subreddit| {
+        SUBREDDIT_LISTINGS
+            .iter()
+            .map(move |listing| CrawlJob::Subreddit(subreddit.clone(), *listing))
+    });
+    queries.chain(subreddits).collect()
+}
+
+struct Crawler {
+    source: Arc<dyn ImageSource>,
+    // one per query status
+    jobs: Vec<CrawlJob>,
+    options: CrawlerOptions,
+    actors: SearchActors,
+    status_actor: Sender<CrawlerMessage>,
+}
+
+impl Crawler {
+    // Pages the source until max_candidates, adding the colors of every
+    // candidate to the index
+    async fn crawl_query(&self, status: &mut CrawlerStatus, i: usize) -> Result<(), ErrorCode> {
+        let mut cursor = None;
+        loop {
+            let page = match &self.jobs[i] {
+                CrawlJob::Query(query) => {
+                    self.source
+                        .next_page(query, &Listing::default(), cursor, Box::new(|_| {}))
+                        .await?
+                }
+                CrawlJob::Subreddit(subreddit, listing) => {
+                    self.source
+                        .next_subreddit_page(subreddit, listing, cursor, Box::new(|_| {}))
+                        .await?
+                }
+            };
+            for candidate in page.candidates {
+                if status.queries[i].candidates == self.options.max_candidates {
+                    return Ok(());
+                }
+                status.queries[i].candidates += 1;
+                match get_colors(&self.actors, &candidate.url).await? {
+                    None => status.queries[i].failed += 1,
+                    Some((colors, cached)) => {
+                        if cached {
+                            status.queries[i].cached += 1;
+                        } else {
+                            status.queries[i].analyzed += 1;
+                        }
+                        let entry = IndexEntry::new(self.source.name(), candidate, &colors);
+                        self.actors
+                            .index
+                            .send(ColorIndexMessage::Insert(entry))
+                            .await
+                            .or(Err(ErrorCode::CannotSendToIndex))?;
+                    }
+                }
+                send_update(&self.status_actor, status).await;
+            }
+            cursor = page.next;
+            if cursor.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn crawl(self, mut status: CrawlerStatus) {
+        loop {
+            status.next_round = None;
+            for i in 0..status.queries.len() {
+                let query = &mut status.queries[i];
+                info!(target: "crawler", "crawling {} for {}", self.source.name(), query.query);
+                *query = QueryStatus {
+                    query: query.query.clone(),
+                    last_started: Some(now()),
+                    last_finished: query.last_finished,
+                    ..QueryStatus::default()
+                };
+                status.running = Some(query.query.clone());
+                send_update(&self.status_actor, &status).await;
+
+                let result = self.crawl_query(&mut status, i).await;
+                let query = &mut status.queries[i];
+                query.last_finished = Some(now());
+                query.last_error = result.err().map(|x| x.to_string());
+                debug!(target: "crawler", "{:?}", query);
+            }
+            status.rounds += 1;
+            status.running = None;
+            status.next_round = Some(now() + self.options.interval.as_secs());
+            send_update(&self.status_actor, &status).await;
+            tokio::time::delay_for(self.options.interval).await;
+        }
+    }
+}
+
+async fn crawler_status(r: Receiver<CrawlerMessage>, mut status: CrawlerStatus) {
+    while let Ok(msg) = r.recv().await {
+        match msg {
+            CrawlerMessage::Status(reply) => {
+                let _ = reply.send(status.clone());
+            }
+            CrawlerMessage::Update(update) => status = update,
+        }
+    }
+}
+
+// Keeps the cache and the color index warm for the configured queries and
+// subreddits, so searches for them are answered from the index. Nothing is
+// crawled without any, but the status is still available.
+pub fn spawn_crawler(
+    source: Arc<dyn ImageSource>,
+    options: CrawlerOptions,
+    actors: SearchActors,
+) -> Sender<CrawlerMessage> {
+    let jobs = get_jobs(&options);
+    let status = CrawlerStatus {
+        source: source.name().to_owned(),
+        interval_ms: options.interval.as_millis() as u64,
+        rounds: 0,
+        running: None,
+        next_round: None,
+        queries: jobs
+            .iter()
+            .map(|x| QueryStatus {
+                query: x.name(),
+                ..QueryStatus::default()
+            })
+            .collect(),
+    };
+    let (w, r) = async_channel::unbounded::<CrawlerMessage>();
+    tokio::spawn(crawler_status(r, status.clone()));
+    if !jobs.is_empty() {
+        let crawler = Crawler {
+            source,
+            jobs,
+            options,
+            actors,
+            status_actor: w.clone(),
+        };
+        tokio::spawn(crawler.crawl(status));
+    }
+    w
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::actors::color_index::spawn_color_index;
+    use crate::color_index::{ColorIndex, NearestQuery};
+    use crate::colors::PaletteColor;
+    use crate::filter::CandidateFilter;
+    use crate::http::OnFetchEvent;
+    use crate::image_source::{Candidate, Page};
+    use crate::ranking::RankingWeights;
+    use async_trait::async_trait;
+    use palette::Lab;
+    use serde_json::Map;
+
+    // Two pages of three candidates, whose url is their lightness, "broken"
+    // when the image cannot be analyzed
+    struct TwoPages;
+
+    #[async_trait]
+    impl ImageSource for TwoPages {
+        fn name(&self) -> &str {
+            "pages"
+        }
+
+        async fn next_page(
+            &self,
+            _: &str,
+            _: &Listing,
+            cursor: Option<String>,
+            _: OnFetchEvent,
+        ) -> Result<Page, image_source::ErrorCode> {
+            let (urls, next) = match cursor {
+                None => (vec!["10", "20", "broken"], Some("2".to_owned())),
+                Some(_) => (vec!["30", "40", "50"], None),
+            };
+            let candidates = urls
+                .into_iter()
+                .map(|x| Candidate {
+                    id: x.to_owned(),
+                    url: x.to_owned(),
+                    metadata: Map::new(),
+                })
+                .collect();
+            Ok(Page { candidates, next })
+        }
+
+        // the same pages, for the "gray" subreddit only
+        async fn next_subreddit_page(
+            &self,
+            subreddit: &str,
+            listing: &Listing,
+            cursor: Option<String>,
+            on_event: OnFetchEvent,
+        ) -> Result<Page, image_source::ErrorCode> {
+            if subreddit != "gray" {
+                return Err(image_source::ErrorCode::InvalidQuery);
+            }
+            self.next_page("", listing, cursor, on_event).await
+        }
+    }
+
+    fn gray(l: f32) -> ImageColors {
+        ImageColors {
+            palette: vec![PaletteColor {
+                color: Lab::new(l, 0.0, 0.0),
+                weight: 1.0,
+            }],
+            layout: vec![Some(Lab::new(l, 0.0, 0.0))],
+            final_url: None,
+        }
+    }
+
+    // Knows the image "20" only
+    fn spawn_test_cache() -> Sender<DominantColorCacheMessage> {
+        let (w, r) = async_channel::unbounded::<DominantColorCacheMessage>();
+        tokio::spawn(async move {
+            while let Ok(msg) = r.recv().await {
+                if let DominantColorCacheMessage::Read(url, reply) = msg {
+                    let _ = reply.send(Some(gray(20.0)).filter(|_| url == "20"));
+                }
+            }
+        });
+        w
+    }
+
+    fn spawn_test_dominant_color() -> Sender<DominantColorDistanceMessage> {
+        let (w, r) = async_channel::unbounded::<DominantColorDistanceMessage>();
+        tokio::spawn(async move {
+            while let Ok(DominantColorDistanceMessage(url, _, reply, _)) = r.recv().await {
+                let _ = reply.send(url.parse().ok().map(|l| (gray(l), 0)));
+            }
+        });
+        w
+    }
+
+    fn test_crawler(options: CrawlerOptions, index: Sender<ColorIndexMessage>) -> Crawler {
+        let (status_actor, _) = async_channel::unbounded();
+        Crawler {
+            source: Arc::new(TwoPages),
+            jobs: get_jobs(&options),
+            options,
+            actors: SearchActors {
+                cache: spawn_test_cache(),
+                dominant_color: spawn_test_dominant_color(),
+                index,
+            },
+            status_actor,
+        }
+    }
+
+    fn test_status(crawler: &Crawler) -> CrawlerStatus {
+        CrawlerStatus {
+            source: "pages".to_owned(),
+            interval_ms: 0,
+            rounds: 0,
+            running: None,
+            next_round: None,
+            queries: crawler
+                .jobs
+                .iter()
+                .map(|x| QueryStatus {
+                    query: x.name(),
+                    ..QueryStatus::default()
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn crawl_indexes_every_page_up_to_the_limit() {
+        let options = CrawlerOptions {
+            queries: vec!["gray".to_owned()],
+            max_candidates: 5,
+            ..CrawlerOptions::default()
+        };
+        let index_actor = spawn_color_index(ColorIndex::new());
+        let crawler = test_crawler(options, index_actor.clone());
+        let mut status = test_status(&crawler);
+        crawler.crawl_query(&mut status, 0).await.unwrap();
+        let query = &status.queries[0];
+        assert_eq!(
+            (query.candidates, query.cached, query.analyzed, query.failed),
+            (5, 1, 3, 1)
+        );
+
+        let (w, s) = oneshot::channel();
+        let nearest = NearestQuery {
+            source: "pages".to_owned(),
+            query: "".to_owned(),
+            target: Target::Palette(gray(0.0).palette),
+            limit: 10,
+            weights: RankingWeights::default(),
+            filter: CandidateFilter::default(),
+        };
+        index_actor
+            .send(ColorIndexMessage::Nearest(nearest, w))
+            .await
+            .unwrap();
+        let ids: Vec<String> = s
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.1.candidate.id)
+            .collect();
+        assert_eq!(ids, vec!["10", "20", "30", "40"]);
+    }
+
+    #[tokio::test]
+    async fn subreddits_are_crawled_by_listing() {
+        let options = CrawlerOptions {
+            queries: vec!["ferrari".to_owned()],
+            subreddits: vec!["gray".to_owned(), "cars".to_owned()],
+            ..CrawlerOptions::default()
+        };
+        let crawler = test_crawler(options, spawn_color_index(ColorIndex::new()));
+        let mut status = test_status(&crawler);
+        let names: Vec<&str> = status.queries.iter().map(|x| x.query.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "ferrari",
+                "r/gray/new",
+                "r/gray/top",
+                "r/cars/new",
+                "r/cars/top"
+            ]
+        );
+        crawler.crawl_query(&mut status, 2).await.unwrap();
+        assert_eq!(status.queries[2].candidates, 6);
+        assert!(crawler.crawl_query(&mut status, 3).await.is_err());
+    }
+}
@@ -1,123 +1,377 @@
-use crate::colors::lab_distance;
+use crate::colors::{
+    normalize_weights, target_distance, ImageColors, Layout, Palette, PaletteColor, Target,
+    LAYOUT_SIZE, MAX_PALETTE_COLORS,
+};
+use crate::formats::{is_animated_webp, sniff_format};
+use crate::http::{Client, FetchEvent, OnFetchEvent};
+use crate::local_source::LocalFiles;
+use crate::sampling::{pick_frames, sample_pixels, SamplingOptions, WeightedPixels};
 use async_channel::Sender;
-use image::imageops::FilterType::Nearest;
-use isahc::prelude::*;
-use kmeans_colors::{get_kmeans, Kmeans, Sort};
-use palette::{Lab, Pixel, Srgb};
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::io::Reader;
+use image::{AnimationDecoder, DynamicImage, Frames, ImageDecoder, ImageFormat, RgbaImage};
+use kmeans_colors::{get_kmeans, Kmeans};
+use palette::Lab;
+use std::io::Cursor;
+use std::path::Path;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum ErrorCode {
     #[error("error on search progress")]
     Error,
+    #[error("unsupported image format")]
+    UnsupportedFormat,
+    #[error("cannot download image: {0}")]
+    CannotDownload(crate::http::ErrorCode),
+    #[error("cannot read image file")]
+    CannotReadFile,
+    #[error("animated webp is not supported")]
+    UnsupportedAnimation,
+    #[error("image is too large to decode")]
+    TooLarge,
 }
 
-fn get_image_pixels(data: &[u8]) -> Result<Vec<Lab>, ErrorCode> {
-    let img = image::load_from_memory(&data).or(Err(ErrorCode::Error))?;
-    let img = img.resize(32, 32, Nearest);
-    let rgb8 = img.to_rgb();
-    let pixels = rgb8.into_raw();
-    Ok(Srgb::from_raw_slice(&pixels)
+// Frames are composed on the whole canvas, so every decoded frame takes
+// frame_bytes. Decoding stops before a frame would go over the budget.
+fn get_animation_frames(
+    frames: Frames,
+    frame_bytes: u64,
+    options: &SamplingOptions,
+) -> Vec<RgbaImage> {
+    let mut decoded_bytes = 0u64;
+    let mut resized = Vec::new();
+    let mut frames = frames.take(options.max_decoded_frames);
+    loop {
+        if decoded_bytes + frame_bytes > options.max_decoded_bytes {
+            break;
+        }
+        let buffer = match frames.next() {
+            Some(Ok(frame)) => frame.into_buffer(),
+            _ => break,
+        };
+        decoded_bytes += frame_bytes;
+        let img = DynamicImage::ImageRgba8(buffer);
+        resized.push(
+            img.resize(options.size, options.size, options.filter)
+                .to_rgba8(),
+        );
+    }
+
+    let picked = pick_frames(resized.len(), options.frames);
+    resized
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| picked.contains(i))
+        .map(|(_, frame)| frame)
+        .collect()
+}
+
+// DCT scaling decodes a jpeg at 1/2, 1/4 or 1/8 of its size, so a large photo
+// never exists in memory at full resolution. The smallest scale that is still
+// at least size pixels wide and tall is used, and resized as usual afterwards.
+fn decode_scaled_jpeg(data: &[u8], options: &SamplingOptions) -> Result<DynamicImage, ErrorCode> {
+    let mut decoder = JpegDecoder::new(Cursor::new(data)).or(Err(ErrorCode::Error))?;
+    let size = options.size.min(u16::MAX as u32) as u16;
+    decoder.scale(size, size).or(Err(ErrorCode::Error))?;
+    get_decoded_bytes(decoder.dimensions(), options)?;
+    DynamicImage::from_decoder(decoder).or(Err(ErrorCode::Error))
+}
+
+// Bytes of an image decoded as rgba, read from the header dimensions so an
+// image over the budget is rejected before any pixel is decoded
+fn get_decoded_bytes(
+    (width, height): (u32, u32),
+    options: &SamplingOptions,
+) -> Result<u64, ErrorCode> {
+    let bytes = width as u64 * height as u64 * 4;
+    if bytes > options.max_decoded_bytes {
+        return Err(ErrorCode::TooLarge);
+    }
+    Ok(bytes)
+}
+
+// Animated GIF and APNG are sampled over several frames, so an intro frame
+// does not decide the color. Animated WebP cannot be decoded and is rejected.
+// Anything else is decoded as a still image.
+fn get_image_frames(data: &[u8], options: &SamplingOptions) -> Result<Vec<RgbaImage>, ErrorCode> {
+    let format = sniff_format(data).ok_or(ErrorCode::UnsupportedFormat)?;
+    let frames = match format {
+        ImageFormat::Gif => match GifDecoder::new(Cursor::new(data)) {
+            Ok(decoder) => {
+                let frame_bytes = get_decoded_bytes(decoder.dimensions(), options)?;
+                get_animation_frames(decoder.into_frames(), frame_bytes, options)
+            }
+            Err(_) => vec![],
+        },
+        ImageFormat::Png => match PngDecoder::new(Cursor::new(data)) {
+            Ok(decoder) if decoder.is_apng() => {
+                let frame_bytes = get_decoded_bytes(decoder.dimensions(), options)?;
+                get_animation_frames(decoder.apng().into_frames(), frame_bytes, options)
+            }
+            _ => vec![],
+        },
+        ImageFormat::WebP if is_animated_webp(data) => return Err(ErrorCode::UnsupportedAnimation),
+        _ => vec![],
+    };
+    if !frames.is_empty() {
+        return Ok(frames);
+    }
+
+    let img = match format {
+        ImageFormat::Jpeg if options.fast_jpeg => decode_scaled_jpeg(data, options)?,
+        _ => {
+            let dimensions = Reader::with_format(Cursor::new(data), format)
+                .into_dimensions()
+                .or(Err(ErrorCode::Error))?;
+            get_decoded_bytes(dimensions, options)?;
+            image::load_from_memory_with_format(&data, format).or(Err(ErrorCode::Error))?
+        }
+    };
+    let img = img.resize(options.size, options.size, options.filter);
+    Ok(vec![img.to_rgba8()])
+}
+
+pub fn get_image_pixels(
+    data: &[u8],
+    options: &SamplingOptions,
+) -> Result<WeightedPixels, ErrorCode> {
+    let mut sample = WeightedPixels::new();
+    for frame in get_image_frames(data, options)?.iter() {
+        sample.append(sample_pixels(frame, options));
+    }
+    if sample.pixels.is_empty() {
+        return Err(ErrorCode::Error);
+    }
+    Ok(sample)
+}
+
+fn get_layout(sample: &WeightedPixels, centroids: &[Lab], indices: &[u8]) -> Layout {
+    let mut cell_weights = vec![vec![0.0f32; centroids.len()]; LAYOUT_SIZE * LAYOUT_SIZE];
+    for ((index, weight), cell) in indices
+        .iter()
+        .zip(sample.weights.iter())
+        .zip(sample.cells.iter())
+    {
+        cell_weights[*cell][*index as usize] += weight;
+    }
+    cell_weights
         .iter()
-        .map(|x| x.into_format().into())
-        .collect())
+        .map(|weights| {
+            weights
+                .iter()
+                .enumerate()
+                .filter(|(_, weight)| **weight > 0.0)
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Less))
+                .map(|(i, _)| centroids[i])
+        })
+        .collect()
 }
 
-fn get_dominant_color(pixels: &Vec<Lab>) -> Option<Lab> {
+pub fn get_dominant_colors(sample: &WeightedPixels) -> ImageColors {
     let runs = 1;
-    let k = 3;
+    let k = MAX_PALETTE_COLORS;
     let max_iter = 1;
     let converge = 0.1;
     let verbose = false;
     let seed = 0;
     let mut result = Kmeans::new();
     (0..runs).for_each(|i| {
-        let run_result = get_kmeans(k, max_iter, converge, verbose, &pixels, seed + i as u64);
+        let run_result = get_kmeans(
+            k,
+            max_iter,
+            converge,
+            verbose,
+            &sample.pixels,
+            seed + i as u64,
+        );
         if run_result.score < result.score {
             result = run_result;
         }
     });
-    let mut res = Lab::sort_indexed_colors(&result.centroids, &result.indices);
 
-    res.sort_unstable_by(|a, b| {
-        b.percentage
-            .partial_cmp(&a.percentage)
+    let mut weights = vec![0.0f32; result.centroids.len()];
+    for (index, weight) in result.indices.iter().zip(sample.weights.iter()) {
+        weights[*index as usize] += weight;
+    }
+    let mut palette: Palette = result
+        .centroids
+        .iter()
+        .zip(weights)
+        .filter(|(_, weight)| *weight > 0.0)
+        .map(|(centroid, weight)| PaletteColor {
+            color: *centroid,
+            weight,
+        })
+        .collect();
+
+    palette.sort_unstable_by(|a, b| {
+        b.weight
+            .partial_cmp(&a.weight)
             .unwrap_or(std::cmp::Ordering::Less)
     });
-    res.first().map(|x| x.centroid)
+    ImageColors {
+        palette: normalize_weights(palette),
+        layout: get_layout(sample, &result.centroids, &result.indices),
+        final_url: None,
+    }
+}
+
+pub fn get_image_dominant_colors(data: &[u8], options: &SamplingOptions) -> Option<ImageColors> {
+    let sample = get_image_pixels(data, options).ok()?;
+    let dominant_colors = get_dominant_colors(&sample);
+    if dominant_colors.palette.is_empty() {
+        None
+    } else {
+        Some(dominant_colors)
+    }
+}
+
+pub async fn get_url_dominant_colors(
+    url: String,
+    client: &Client,
+    options: &SamplingOptions,
+    on_event: impl FnMut(&FetchEvent),
+) -> Result<Option<ImageColors>, ErrorCode> {
+    let fetched = client
+        .fetch_reporting(&url, on_event)
+        .await
+        .map_err(ErrorCode::CannotDownload)?;
+    Ok(
+        get_image_dominant_colors(&fetched.body, options).map(|dominant_colors| ImageColors {
+            final_url: Some(fetched.url),
+            ..dominant_colors
+        }),
+    )
+}
+
+pub async fn get_file_dominant_colors(
+    path: &Path,
+    options: &SamplingOptions,
+) -> Result<Option<ImageColors>, ErrorCode> {
+    let data = tokio::fs::read(path)
+        .await
+        .or(Err(ErrorCode::CannotReadFile))?;
+    Ok(get_image_dominant_colors(&data, options))
 }
 
 async fn handle(
-    DominantColorDistanceMessage(url, desired_color, reply): DominantColorDistanceMessage,
+    DominantColorDistanceMessage(url, target, reply, on_event): DominantColorDistanceMessage,
+    client: &Client,
+    options: &SamplingOptions,
+    local_files: &Option<LocalFiles>,
 ) -> Result<(), ErrorCode> {
-    let mut url = url;
-    let mut tries = 10u8;
-    let response = loop {
-        if tries <= 0 {
-            break None;
-        }
-
-        let response = Request::get(url)
-            .body(())
-            .or(Err(ErrorCode::Error))?
-            .send_async()
-            .await
-            .or(Err(ErrorCode::Error))?;
-
-        let status = response.status().as_u16();
-        if status == 200 {
-            break Some(response);
-        } else if status == 301 {
-            let headers = response.headers();
-            let location = headers.get("Location").ok_or(ErrorCode::Error)?;
-            url = location.to_str().or(Err(ErrorCode::Error))?.to_owned();
-            tries -= 1;
-        } else {
-            break None;
-        }
+    let local_path = local_files.as_ref().and_then(|x| x.path(&url));
+    let dominant_colors = match local_path {
+        Some(path) => get_file_dominant_colors(&path, options).await?,
+        None => get_url_dominant_colors(url, client, options, on_event).await?,
     };
-
-    match response {
-        None => {
-            reply.send(None).or(Err(ErrorCode::Error))?;
-        }
-        Some(mut response) => {
-            let mut img_data = Vec::new();
-            response.copy_to(&mut img_data).or(Err(ErrorCode::Error))?;
-            let result = match get_image_pixels(&img_data) {
-                Err(_) => None,
-                Ok(pixels) => match get_dominant_color(&pixels) {
-                    None => None,
-                    Some(dominant_color) => {
-                        let distance = lab_distance(&desired_color, &dominant_color);
-                        Some((dominant_color, distance as u32))
-                    }
-                },
-            };
-            reply.send(result).or(Err(ErrorCode::Error))?;
-        }
-    }
+    let result = dominant_colors.map(|dominant_colors| {
+        let distance = target_distance(&target, &dominant_colors);
+        (dominant_colors, distance as u32)
+    });
+    reply.send(result).or(Err(ErrorCode::Error))?;
     Ok(())
 }
 
 pub struct DominantColorDistanceMessage(
     pub String,
-    pub Lab,
-    pub oneshot::Sender<Option<(Lab, u32)>>,
+    pub Target,
+    pub oneshot::Sender<Option<(ImageColors, u32)>>,
+    pub OnFetchEvent,
 );
-async fn test_color_actor(r: async_channel::Receiver<DominantColorDistanceMessage>) {
+async fn test_color_actor(
+    r: async_channel::Receiver<DominantColorDistanceMessage>,
+    client: Client,
+    options: SamplingOptions,
+    local_files: Option<LocalFiles>,
+) {
     loop {
         match r.recv().await {
             Ok(msg) => {
-                let _ = handle(msg).await;
+                let _ = handle(msg, &client, &options, &local_files).await;
             }
             Err(_) => {}
         }
     }
 }
 
-pub fn spawn_dominant_color() -> Sender<DominantColorDistanceMessage> {
+// Urls of local_files are read from disk, anything else is downloaded
+pub fn spawn_dominant_color(
+    client: Client,
+    options: SamplingOptions,
+    local_files: Option<LocalFiles>,
+) -> Sender<DominantColorDistanceMessage> {
     let (w, r) = async_channel::unbounded::<DominantColorDistanceMessage>();
-    tokio::spawn(test_color_actor(r));
+    tokio::spawn(test_color_actor(r, client, options, local_files));
     w
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Frame, ImageOutputFormat, Rgba};
+
+    fn animated_gif(frames: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            let frames = (0..frames)
+                .map(|i| Frame::new(RgbaImage::from_pixel(4, 4, Rgba([i as u8 * 40, 0, 0, 255]))));
+            encoder.encode_frames(frames).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn frames_stop_before_the_decoding_budget() {
+        let frame_bytes = 4 * 4 * 4;
+        let options = SamplingOptions {
+            max_decoded_bytes: frame_bytes * 2 + frame_bytes / 2,
+            ..SamplingOptions::default()
+        };
+        let frames = get_image_frames(&animated_gif(5), &options).unwrap();
+        assert_eq!(frames.len(), 2);
+
+        // not even the first frame is decoded over the budget
+        let options = SamplingOptions {
+            max_decoded_bytes: frame_bytes - 1,
+            ..SamplingOptions::default()
+        };
+        assert!(matches!(
+            get_image_frames(&animated_gif(5), &options),
+            Err(ErrorCode::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn still_images_over_the_budget_are_rejected() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(8, 8));
+        for format in &[ImageOutputFormat::Png, ImageOutputFormat::Jpeg(90)] {
+            let mut data = Vec::new();
+            image.write_to(&mut data, format.clone()).unwrap();
+            let options = |max_decoded_bytes| SamplingOptions {
+                max_decoded_bytes,
+                ..SamplingOptions::default()
+            };
+            assert!(get_image_frames(&data, &options(8 * 8 * 4)).is_ok());
+            assert!(matches!(
+                get_image_frames(&data, &options(8 * 8 * 4 - 1)),
+                Err(ErrorCode::TooLarge)
+            ));
+        }
+    }
+
+    #[test]
+    fn animated_webp_is_rejected() {
+        let mut data = b"RIFF\x1e\0\0\0WEBPVP8X\x0a\0\0\0".to_vec();
+        data.extend_from_slice(&[0x02, 0, 0, 0, 3, 0, 0, 3, 0, 0]);
+        assert!(matches!(
+            get_image_frames(&data, &SamplingOptions::default()),
+            Err(ErrorCode::UnsupportedAnimation)
+        ));
+    }
+}
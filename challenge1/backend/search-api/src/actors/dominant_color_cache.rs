@@ -5,6 +5,7 @@ use std::collections::HashMap;
 use std::io::prelude::*;
 use thiserror::Error;
 
+use crate::colors::{ImageColors, Layout, PaletteColor};
 use crate::loggable::Loggable;
 
 type OneSender<T> = oneshot::Sender<T>;
@@ -19,61 +20,140 @@ pub enum ErrorCode {
     CannotParseColorComponent,
 }
 
+fn parse_color_component(s: &str) -> Result<f32, ErrorCode> {
+    s.parse::<f32>()
+        .or(Err(ErrorCode::CannotParseColorComponent))
+}
+
+fn parse_layout_cell(s: &str) -> Result<Option<Lab>, ErrorCode> {
+    if s == "-" {
+        return Ok(None);
+    }
+    let parts: Vec<&str> = s.split(',').collect();
+    if parts.len() != 3 {
+        return Err(ErrorCode::CannotParseColorComponent);
+    }
+    Ok(Some(Lab::from_components((
+        parse_color_component(parts[0])?,
+        parse_color_component(parts[1])?,
+        parse_color_component(parts[2])?,
+    ))))
+}
+
+fn format_layout(layout: &Layout) -> String {
+    let cells: Vec<String> = layout
+        .iter()
+        .map(|x| match x {
+            None => "-".to_owned(),
+            Some(x) => format!("{},{},{}", x.l, x.a, x.b),
+        })
+        .collect();
+    format!("layout {}\n", cells.join(" "))
+}
+
+// One "l a b weight" line per color, a "layout" line with the color of each
+// cell and a "url" line with the url after redirects. Older cache files have
+// only the dominant color, one component per line, and no layout.
+fn parse_cache_file(txt: &str) -> Result<ImageColors, ErrorCode> {
+    let lines: Vec<&str> = txt.split("\n").filter(|x| !x.is_empty()).collect();
+    if lines.len() == 3 && lines.iter().all(|x| !x.contains(' ')) {
+        let color = Lab::from_components((
+            parse_color_component(lines[0])?,
+            parse_color_component(lines[1])?,
+            parse_color_component(lines[2])?,
+        ));
+        return Ok(ImageColors {
+            palette: vec![PaletteColor { color, weight: 1.0 }],
+            layout: vec![],
+            final_url: None,
+        });
+    }
+
+    let mut palette = Vec::with_capacity(lines.len());
+    let mut layout = vec![];
+    let mut final_url = None;
+    for line in lines {
+        let parts: Vec<&str> = line.split(' ').collect();
+        if parts[0] == "url" {
+            final_url = Some(parts[1..].join(" "));
+            continue;
+        }
+        if parts[0] == "layout" {
+            layout = parts[1..]
+                .iter()
+                .map(|x| parse_layout_cell(x))
+                .collect::<Result<Layout, ErrorCode>>()?;
+            continue;
+        }
+        if parts.len() != 4 {
+            return Err(ErrorCode::CannotParseColorComponent);
+        }
+        palette.push(PaletteColor {
+            color: Lab::from_components((
+                parse_color_component(parts[0])?,
+                parse_color_component(parts[1])?,
+                parse_color_component(parts[2])?,
+            )),
+            weight: parse_color_component(parts[3])?,
+        });
+    }
+    Ok(ImageColors {
+        palette,
+        layout,
+        final_url,
+    })
+}
+
 fn handle_write(
     msg: DominantColorCacheMessage,
-    map: &mut HashMap<String, Lab>,
+    map: &mut HashMap<String, ImageColors>,
 ) -> Result<(), ErrorCode> {
     match msg {
-        DominantColorCacheMessage::Write(url, dominant_color) => {
+        DominantColorCacheMessage::Write(url, dominant_colors) => {
             let _ = std::fs::create_dir(".cache");
             let digest = md5::compute(&url);
             let path = format!(".cache/{:x}.txt", digest);
             let mut f = std::fs::File::create(path).or(Err(ErrorCode::Error))?;
             use std::io::prelude::*;
-            f.write_all(format!("{}\n", dominant_color.l).as_bytes())
-                .or(Err(ErrorCode::Error))?;
-            f.write_all(format!("{}\n", dominant_color.a).as_bytes())
+            for x in dominant_colors.palette.iter() {
+                f.write_all(
+                    format!("{} {} {} {}\n", x.color.l, x.color.a, x.color.b, x.weight).as_bytes(),
+                )
                 .or(Err(ErrorCode::Error))?;
-            f.write_all(format!("{}\n", dominant_color.b).as_bytes())
+            }
+            f.write_all(format_layout(&dominant_colors.layout).as_bytes())
                 .or(Err(ErrorCode::Error))?;
+            if let Some(final_url) = &dominant_colors.final_url {
+                f.write_all(format!("url {}\n", final_url).as_bytes())
+                    .or(Err(ErrorCode::Error))?;
+            }
             debug!(target: "distance_cache", "cache written: {:x} {}", digest, url);
-            map.insert(url.clone(), dominant_color);
+            map.insert(url.clone(), dominant_colors);
         }
         DominantColorCacheMessage::Read(url, reply) => {
             trace!(target: "dominant_color_cache", "Read({}, reply)", url);
-            if let Some(dominant_color) = map.get(&url) {
+            if let Some(dominant_colors) = map.get(&url) {
                 trace!(target: "dominant_color_cache", "found on map");
                 reply
-                    .send(Some(dominant_color.clone()))
+                    .send(Some(dominant_colors.clone()))
                     .or(Err(ErrorCode::Error))?;
             } else {
                 trace!(target: "dominant_color_cache", "reading from .cache");
                 let _ = std::fs::create_dir(".cache");
                 let digest = md5::compute(&url);
                 let path = format!(".cache/{:x}.txt", digest);
-                let dominant_color = match std::fs::File::open(path) {
+                let dominant_colors = match std::fs::File::open(path) {
                     Err(_) => None,
                     Ok(mut f) => {
                         let mut txt = String::with_capacity(100);
                         f.read_to_string(&mut txt)
                             .or(Err(ErrorCode::CannotReadCacheFile))?;
-                        let parts: Vec<&str> = txt.split("\n").collect();
-                        let dominant_color = Lab::from_components((
-                            parts[0]
-                                .parse::<f32>()
-                                .or(Err(ErrorCode::CannotParseColorComponent))?,
-                            parts[1]
-                                .parse::<f32>()
-                                .or(Err(ErrorCode::CannotParseColorComponent))?,
-                            parts[2]
-                                .parse::<f32>()
-                                .or(Err(ErrorCode::CannotParseColorComponent))?,
-                        ));
-                        map.insert(url.clone(), dominant_color);
-                        Some(dominant_color)
+                        let dominant_colors = parse_cache_file(&txt)?;
+                        map.insert(url.clone(), dominant_colors.clone());
+                        Some(dominant_colors)
                     }
                 };
-                reply.send(dominant_color).or(Err(ErrorCode::Error))?;
+                reply.send(dominant_colors).or(Err(ErrorCode::Error))?;
             }
         }
     }
@@ -81,8 +161,8 @@ fn handle_write(
 }
 
 pub enum DominantColorCacheMessage {
-    Write(String, Lab),
-    Read(String, OneSender<Option<Lab>>),
+    Write(String, ImageColors),
+    Read(String, OneSender<Option<ImageColors>>),
 }
 async fn distance_cache(r: Receiver<DominantColorCacheMessage>) {
     let mut map = HashMap::new();
@@ -101,3 +181,27 @@ pub fn spawn_dominant_color_cache() -> Sender<DominantColorCacheMessage> {
     tokio::spawn(distance_cache(r));
     w
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::colors::Target;
+
+    #[test]
+    fn legacy_cache_files_have_no_layout() {
+        let layout = Target::Layout(vec![Some(Lab::new(50.0, 0.0, 0.0))]);
+        let single = parse_cache_file("53.2\n80.1\n67.2\n").unwrap();
+        assert_eq!(single.palette.len(), 1);
+        let palette = parse_cache_file("53.2 80.1 67.2 0.7\n0 0 0 0.3\n").unwrap();
+        assert_eq!(palette.palette.len(), 2);
+        for colors in [single, palette].iter() {
+            assert!(!colors.has_layout());
+            assert!(!colors.can_compare(&layout));
+            assert!(colors.can_compare(&Target::Palette(vec![])));
+        }
+
+        let current = parse_cache_file("53.2 80.1 67.2 1\nlayout 53.2,80.1,67.2 -\n").unwrap();
+        assert_eq!(current.layout.len(), 2);
+        assert!(current.can_compare(&layout));
+    }
+}
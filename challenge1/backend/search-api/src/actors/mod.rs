@@ -1,2 +1,6 @@
+pub mod color_index;
+pub mod crawler;
 pub mod dominant_color;
 pub mod dominant_color_cache;
+pub mod rate_limit;
+pub mod reddit_token;
@@ -0,0 +1,185 @@
+use async_channel::{Receiver, Sender};
+use isahc::http::HeaderMap;
+use log::debug;
+use std::time::{Duration, Instant};
+
+type OneSender<T> = oneshot::Sender<T>;
+
+// how long to stop after a 429 that does not say when to come back
+const DEFAULT_THROTTLE: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub remaining: f32,
+    // until the window resets
+    pub reset: Duration,
+}
+
+fn header_f32(headers: &HeaderMap, name: &str) -> Option<f32> {
+    headers
+        .get(name)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|x| x.is_finite() && *x >= 0.0)
+}
+
+// Reddit sends X-Ratelimit-Remaining and X-Ratelimit-Reset, in seconds, with
+// every response. A 429 stops every request until the reset, whatever the
+// remaining header says.
+pub fn parse_rate_limit(status: u16, headers: &HeaderMap) -> Option<RateLimit> {
+    let reset = header_f32(headers, "x-ratelimit-reset")
+        .or_else(|| header_f32(headers, "retry-after"))
+        .map(Duration::from_secs_f32);
+    if status == 429 {
+        return Some(RateLimit {
+            remaining: 0.0,
+            reset: reset.unwrap_or(DEFAULT_THROTTLE),
+        });
+    }
+    Some(RateLimit {
+        remaining: header_f32(headers, "x-ratelimit-remaining")?,
+        reset: reset?,
+    })
+}
+
+pub struct Schedule {
+    // unknown until a response reports it
+    remaining: Option<f32>,
+    reset_at: Instant,
+    next_slot: Instant,
+}
+
+impl Schedule {
+    pub fn new(now: Instant) -> Schedule {
+        Schedule {
+            remaining: None,
+            reset_at: now,
+            next_slot: now,
+        }
+    }
+
+    // Reserves the next request and returns how long to wait before sending
+    // it. Requests are spread evenly over what is left of the window, and
+    // wait for the reset once nothing remains.
+    pub fn acquire(&mut self, now: Instant) -> Duration {
+        let mut start = self.next_slot.max(now);
+        match self.remaining {
+            Some(remaining) if self.reset_at > start => {
+                if remaining < 1.0 {
+                    start = self.reset_at;
+                    self.remaining = None;
+                    self.next_slot = start;
+                } else {
+                    self.next_slot = start + (self.reset_at - start).div_f32(remaining);
+                    self.remaining = Some(remaining - 1.0);
+                }
+            }
+            _ => {
+                self.remaining = None;
+                self.next_slot = start;
+            }
+        }
+        start - now
+    }
+
+    pub fn update(&mut self, limit: RateLimit, now: Instant) {
+        self.remaining = Some(limit.remaining);
+        self.reset_at = now + limit.reset;
+        if limit.remaining < 1.0 {
+            self.next_slot = self.next_slot.max(self.reset_at);
+        }
+    }
+}
+
+pub enum RateLimitMessage {
+    // replies with the time to wait before sending the request
+    Acquire(OneSender<Duration>),
+    Update(RateLimit),
+}
+
+async fn rate_limit(r: Receiver<RateLimitMessage>) {
+    let mut schedule = Schedule::new(Instant::now());
+    loop {
+        match r.recv().await {
+            Ok(RateLimitMessage::Acquire(reply)) => {
+                let wait = schedule.acquire(Instant::now());
+                debug!(target: "rate_limit", "request scheduled in {:?}", wait);
+                let _ = reply.send(wait);
+            }
+            Ok(RateLimitMessage::Update(limit)) => {
+                debug!(target: "rate_limit", "{:?}", limit);
+                schedule.update(limit, Instant::now());
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+pub fn spawn_rate_limit() -> Sender<RateLimitMessage> {
+    let (w, r) = async_channel::unbounded::<RateLimitMessage>();
+    tokio::spawn(rate_limit(r));
+    w
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limit(remaining: u8, reset: u8) -> RateLimit {
+        RateLimit {
+            remaining: remaining as f32,
+            reset: Duration::from_secs(reset as u64),
+        }
+    }
+
+    #[quickcheck]
+    fn unknown_limits_never_wait(n: u8) -> bool {
+        let now = Instant::now();
+        let mut schedule = Schedule::new(now);
+        (0..n).all(|_| schedule.acquire(now) == Duration::from_secs(0))
+    }
+
+    #[quickcheck]
+    fn requests_are_spread_over_the_window(remaining: u8, reset: u8) -> bool {
+        let (remaining, reset) = (1 + remaining % 100, 1 + reset % 200);
+        let now = Instant::now();
+        let mut schedule = Schedule::new(now);
+        schedule.update(limit(remaining, reset), now);
+        let waits: Vec<Duration> = (0..remaining).map(|_| schedule.acquire(now)).collect();
+        waits.windows(2).all(|x| x[0] <= x[1])
+            && *waits.last().unwrap() < Duration::from_secs(reset as u64)
+    }
+
+    #[quickcheck]
+    fn nothing_remaining_waits_for_the_reset(reset: u8, n: u8) -> bool {
+        let now = Instant::now();
+        let mut schedule = Schedule::new(now);
+        schedule.update(limit(0, reset), now);
+        (0..=n).all(|_| schedule.acquire(now) >= Duration::from_secs(reset as u64))
+    }
+
+    #[test]
+    fn parse_rate_limit_reads_reddit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Ratelimit-Remaining", "598.0".parse().unwrap());
+        headers.insert("X-Ratelimit-Reset", "120".parse().unwrap());
+        assert_eq!(
+            parse_rate_limit(200, &headers),
+            Some(RateLimit {
+                remaining: 598.0,
+                reset: Duration::from_secs(120)
+            })
+        );
+        assert_eq!(
+            parse_rate_limit(429, &HeaderMap::new()),
+            Some(RateLimit {
+                remaining: 0.0,
+                reset: DEFAULT_THROTTLE
+            })
+        );
+        assert_eq!(parse_rate_limit(200, &HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,150 @@
+use async_channel::{Receiver, Sender};
+use log::debug;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::http::{self, Client};
+use crate::loggable::Loggable;
+
+type OneSender<T> = oneshot::Sender<T>;
+
+// tokens are renewed this long before reddit expires them, so a request
+// never leaves with a token about to expire
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum ErrorCode {
+    #[error("cannot request token: {0}")]
+    CannotRequestToken(#[from] http::ErrorCode),
+    #[error("cannot parse token response")]
+    InvalidResponse,
+}
+
+// Credentials of a reddit "script" or "web" app, used for application only
+// authentication
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub client_id: String,
+    pub client_secret: String,
+    pub token_url: String,
+}
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    // seconds
+    expires_in: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    // Authorization header value
+    pub authorization: String,
+    pub expires_at: Instant,
+}
+
+impl Token {
+    pub fn is_valid(&self, now: Instant) -> bool {
+        now + EXPIRY_MARGIN < self.expires_at
+    }
+}
+
+// Reddit answers errors such as invalid credentials with a 200 and an
+// {"error": ...} body, which has no access_token and is rejected here
+pub fn parse_token(body: &[u8], now: Instant) -> Result<Token, ErrorCode> {
+    let response =
+        serde_json::from_slice::<AccessTokenResponse>(body).or(Err(ErrorCode::InvalidResponse))?;
+    Ok(Token {
+        authorization: format!("bearer {}", response.access_token),
+        expires_at: now + Duration::from_secs(response.expires_in),
+    })
+}
+
+async fn request_token(client: &Client, credentials: &Credentials) -> Result<Token, ErrorCode> {
+    let basic = base64::encode(format!(
+        "{}:{}",
+        credentials.client_id, credentials.client_secret
+    ));
+    let body = client
+        .post_form(
+            &credentials.token_url,
+            &format!("Basic {}", basic),
+            "grant_type=client_credentials",
+        )
+        .await?;
+    parse_token(&body, Instant::now())
+}
+
+pub enum RedditTokenMessage {
+    // replies with the Authorization header value, None when reddit does not
+    // give a token
+    Get(OneSender<Option<String>>),
+    // reddit rejected this Authorization header value. Requests that used an
+    // older token do not throw away a token that was already renewed.
+    Invalidate(String),
+}
+
+async fn reddit_token(r: Receiver<RedditTokenMessage>, client: Client, credentials: Credentials) {
+    let mut token: Option<Token> = None;
+    loop {
+        match r.recv().await {
+            Ok(RedditTokenMessage::Get(reply)) => {
+                // requests keep waiting here while the token is renewed, so
+                // only one of them asks reddit for it
+                if !token.as_ref().map_or(false, |x| x.is_valid(Instant::now())) {
+                    debug!(target: "reddit_token", "requesting token");
+                    token = request_token(&client, &credentials)
+                        .await
+                        .log_if_error()
+                        .ok();
+                }
+                let _ = reply.send(token.as_ref().map(|x| x.authorization.clone()));
+            }
+            Ok(RedditTokenMessage::Invalidate(authorization)) => {
+                if token
+                    .as_ref()
+                    .map_or(false, |x| x.authorization == authorization)
+                {
+                    debug!(target: "reddit_token", "token rejected");
+                    token = None;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+pub fn spawn_reddit_token(client: Client, credentials: Credentials) -> Sender<RedditTokenMessage> {
+    let (w, r) = async_channel::unbounded::<RedditTokenMessage>();
+    tokio::spawn(reddit_token(r, client, credentials));
+    w
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[quickcheck]
+    fn tokens_are_renewed_before_they_expire(expires_in: u32, elapsed: u32) -> bool {
+        let now = Instant::now();
+        let body = format!(
+            concat!(
+                "{{\"access_token\":\"abc\",\"token_type\":\"bearer\",",
+                "\"expires_in\":{},\"scope\":\"*\"}}"
+            ),
+            expires_in
+        );
+        let token = parse_token(body.as_bytes(), now).unwrap();
+        let later = now + Duration::from_secs(elapsed as u64);
+        token.authorization == "bearer abc"
+            && token.is_valid(later)
+                == (elapsed as u64 + EXPIRY_MARGIN.as_secs() < expires_in as u64)
+    }
+
+    #[test]
+    fn error_responses_are_not_tokens() {
+        let body = b"{\"error\": \"invalid_grant\"}";
+        assert!(parse_token(body, Instant::now()).is_err());
+    }
+}
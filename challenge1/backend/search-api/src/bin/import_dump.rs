@@ -0,0 +1,26 @@
+use std::path::Path;
+
+use search_api::config::Config;
+use search_api::corpus::{import_posts, open_dump};
+
+// Imports reddit submission dumps, .jsonl or .zst, into the corpus searched
+// by the "corpus" source
+fn main() {
+    pretty_env_logger::init();
+    let config = Config::from_env();
+
+    let dumps: Vec<String> = std::env::args().skip(1).collect();
+    if dumps.is_empty() {
+        eprintln!("usage: import_dump <dump.jsonl|dump.zst>...");
+        std::process::exit(2);
+    }
+    for dump in dumps {
+        match open_dump(Path::new(&dump)).and_then(|x| import_posts(x, &config.corpus)) {
+            Ok(stats) => println!("{}: {:?}", dump, stats),
+            Err(err) => {
+                eprintln!("{}: {}", dump, err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
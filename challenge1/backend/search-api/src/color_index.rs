@@ -0,0 +1,557 @@
+use palette::Lab;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::colors::{target_distance, ImageColors, PaletteColor, Target, MAX_PALETTE_COLORS};
+use crate::filter::{has_unknown_nsfw, CandidateFilter};
+use crate::image_source::Candidate;
+use crate::kd_tree::{KdTree, Point};
+use crate::query::parse_query;
+use crate::ranking::RankingWeights;
+use crate::text::{get_words, TextIndex};
+
+// New entries are searched linearly until there are enough of them to
+// rebuild the tree
+const MIN_REBUILD_POINTS: usize = 256;
+
+// An analyzed candidate of a source, one json per line in the index file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub source: String,
+    pub candidate: Candidate,
+    // l, a, b and weight of each palette color
+    pub palette: Vec<[f32; 4]>,
+    pub layout: Vec<Option<[f32; 3]>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_url: Option<String>,
+}
+
+pub struct NearestQuery {
+    pub source: String,
+    pub query: String,
+    pub target: Target,
+    pub limit: usize,
+    pub weights: RankingWeights,
+    pub filter: CandidateFilter,
+}
+
+fn to_point(color: &Lab) -> Point {
+    [color.l, color.a, color.b]
+}
+
+fn to_lab(point: &[f32]) -> Lab {
+    Lab::new(point[0], point[1], point[2])
+}
+
+impl IndexEntry {
+    pub fn new(source: &str, candidate: Candidate, colors: &ImageColors) -> IndexEntry {
+        IndexEntry {
+            source: source.to_owned(),
+            candidate,
+            palette: colors
+                .palette
+                .iter()
+                .map(|x| [x.color.l, x.color.a, x.color.b, x.weight])
+                .collect(),
+            layout: colors
+                .layout
+                .iter()
+                .map(|x| x.as_ref().map(to_point))
+                .collect(),
+            final_url: colors.final_url.clone(),
+        }
+    }
+
+    pub fn colors(&self) -> ImageColors {
+        ImageColors {
+            palette: self
+                .palette
+                .iter()
+                .map(|x| PaletteColor {
+                    color: to_lab(x),
+                    weight: x[3],
+                })
+                .collect(),
+            layout: self
+                .layout
+                .iter()
+                .map(|x| x.as_ref().map(|x| to_lab(x)))
+                .collect(),
+            final_url: self.final_url.clone(),
+        }
+    }
+
+    // Written from colors cached before layouts existed, or from a post
+    // listed before its nsfw flag was kept. Replaced when the candidate is
+    // listed and analyzed again.
+    fn is_stale(&self) -> bool {
+        self.layout.is_empty() || has_unknown_nsfw(&self.candidate)
+    }
+
+    fn can_compare(&self, target: &Target) -> bool {
+        match target {
+            Target::Palette(_) => true,
+            Target::Layout(_) => !self.layout.is_empty(),
+        }
+    }
+}
+
+// Line of the entry in the index file
+pub fn get_line(entry: &IndexEntry) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+// Blocking, callers in async tasks run it on the blocking pool
+pub fn append_line(file: &Path, line: &[u8]) -> io::Result<()> {
+    if let Some(directory) = file.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?
+        .write_all(line)
+}
+
+// Words of the text metadata, like reddit titles and subreddits or local tags
+pub fn get_candidate_words(candidate: &Candidate) -> Vec<String> {
+    let mut words = vec![];
+    for value in candidate.metadata.values() {
+        match value {
+            Value::String(s) => words.extend(get_words(s)),
+            Value::Array(values) => {
+                for s in values.iter().filter_map(|x| x.as_str()) {
+                    words.extend(get_words(s));
+                }
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+// Candidates whose colors are already known, searchable by their nearest
+// palette colors in Lab space. Entries are appended to the file as they are
+// inserted and read back when the index is opened.
+pub struct ColorIndex {
+    file: Option<PathBuf>,
+    entries: Vec<IndexEntry>,
+    words: Vec<Vec<String>>,
+    // documents are the words of each entry, in the same order
+    text: TextIndex,
+    // position of each entry by source and candidate id
+    keys: HashMap<(String, String), usize>,
+    tree: KdTree<usize>,
+    // palette colors not in the tree yet
+    pending: Vec<(Point, usize)>,
+}
+
+impl ColorIndex {
+    // Kept in memory only
+    pub fn new() -> ColorIndex {
+        ColorIndex {
+            file: None,
+            entries: vec![],
+            words: vec![],
+            text: TextIndex::new(),
+            keys: HashMap::new(),
+            tree: KdTree::new(vec![]),
+            pending: vec![],
+        }
+    }
+
+    // Invalid lines, like one cut by a crash, are skipped
+    pub fn open(file: PathBuf) -> io::Result<ColorIndex> {
+        let mut index = ColorIndex::new();
+        match File::open(&file) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+            Ok(f) => {
+                for line in BufReader::new(f).split(b'\n') {
+                    if let Ok(entry) = serde_json::from_slice::<IndexEntry>(&line?) {
+                        index.add(entry);
+                    }
+                }
+                index.rebuild();
+            }
+        }
+        index.file = Some(file);
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Where the entry goes, None when the index already has it. Stale entries
+    // are replaced by up to date ones.
+    fn get_position(&self, entry: &IndexEntry) -> Option<usize> {
+        let key = (entry.source.clone(), entry.candidate.id.clone());
+        match self.keys.get(&key) {
+            None => Some(self.entries.len()),
+            Some(i) if self.entries[*i].is_stale() && !entry.is_stale() => Some(*i),
+            Some(_) => None,
+        }
+    }
+
+    fn add(&mut self, entry: IndexEntry) -> bool {
+        let i = match self.get_position(&entry) {
+            Some(i) => i,
+            None => return false,
+        };
+        for color in entry.palette.iter() {
+            self.pending.push(([color[0], color[1], color[2]], i));
+        }
+        let words = get_candidate_words(&entry.candidate);
+        if i < self.entries.len() {
+            // the text index keeps the words it had, they are the same post
+            self.words[i] = words;
+            self.entries[i] = entry;
+        } else {
+            let key = (entry.source.clone(), entry.candidate.id.clone());
+            self.keys.insert(key, i);
+            self.text.add(&words);
+            self.words.push(words);
+            self.entries.push(entry);
+        }
+        true
+    }
+
+    fn rebuild(&mut self) {
+        let tree = std::mem::replace(&mut self.tree, KdTree::new(vec![]));
+        let mut points = tree.into_points();
+        points.append(&mut self.pending);
+        self.tree = KdTree::new(points);
+    }
+
+    // File the entries are appended to, None for an index kept in memory
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    // Entries already in the index are ignored, colors of an image do not
+    // change, unless they are stale
+    pub fn accepts(&self, entry: &IndexEntry) -> bool {
+        self.get_position(entry).is_some()
+    }
+
+    // Writes the entry and adds it. The file keeps both lines of a replaced
+    // stale entry, the last one wins when it is read back.
+    pub fn insert(&mut self, entry: IndexEntry) -> io::Result<bool> {
+        if !self.accepts(&entry) {
+            return Ok(false);
+        }
+        if let Some(file) = &self.file {
+            append_line(file, &get_line(&entry)?)?;
+        }
+        Ok(self.insert_in_memory(entry))
+    }
+
+    // Adds an entry already written to the file by the caller
+    pub fn insert_in_memory(&mut self, entry: IndexEntry) -> bool {
+        if !self.add(entry) {
+            return false;
+        }
+        if self.pending.len() > MIN_REBUILD_POINTS.max(self.tree.len() / 4) {
+            self.rebuild();
+        }
+        true
+    }
+
+    // Up to limit entries of the source matching the query and the filter,
+    // ordered by their color distance and text relevance. Entries come from
+    // the nearest colors of the tree and the most relevant texts, the distance
+    // is the same one used for analyzed images.
+    pub fn nearest(&self, query: &NearestQuery) -> Vec<(f32, &IndexEntry)> {
+        let parsed = parse_query(&query.query);
+        let words = parsed.words();
+        let accept = |i: &usize| {
+            let entry = &self.entries[*i];
+            entry.source == query.source
+                && entry.can_compare(&query.target)
+                && parsed.matches(&self.words[*i])
+                && query.filter.matches(&entry.candidate)
+        };
+        let colors: Vec<Point> = match &query.target {
+            Target::Palette(palette) => palette.iter().map(|x| to_point(&x.color)).collect(),
+            Target::Layout(layout) => layout.iter().flatten().map(to_point).collect(),
+        };
+
+        let mut found = HashSet::new();
+        let k = query.limit * MAX_PALETTE_COLORS;
+        for color in colors.iter() {
+            found.extend(
+                self.tree
+                    .nearest(color, k, accept)
+                    .into_iter()
+                    .map(|x| *x.1),
+            );
+        }
+        found.extend(self.pending.iter().map(|x| x.1).filter(accept));
+
+        let relevance = self.text.scores(&words);
+        let mut relevant: Vec<(&usize, &f32)> = relevance.iter().filter(|x| accept(x.0)).collect();
+        relevant.sort_by(|a, b| b.1.partial_cmp(a.1).unwrap_or(Ordering::Equal));
+        found.extend(relevant.iter().take(k).map(|x| *x.0));
+        let max_relevance = relevant.first().map_or(0.0, |x| *x.1);
+
+        let mut nearest: Vec<(f32, f32, &IndexEntry)> = found
+            .into_iter()
+            .map(|i| {
+                let entry = &self.entries[i];
+                let distance = target_distance(&query.target, &entry.colors());
+                let relevance = relevance.get(&i).cloned().unwrap_or(0.0);
+                let cost = query.weights.cost(distance, relevance, max_relevance);
+                (cost, distance, entry)
+            })
+            .collect();
+        nearest.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        nearest
+            .into_iter()
+            .take(query.limit)
+            .map(|x| (x.1, x.2))
+            .collect()
+    }
+}
+
+impl Default for ColorIndex {
+    fn default() -> ColorIndex {
+        ColorIndex::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Map;
+
+    fn entry(source: &str, id: &str, title: &str, l: f32) -> IndexEntry {
+        let mut metadata = Map::new();
+        metadata.insert("title".to_owned(), Value::from(title));
+        let candidate = Candidate {
+            id: id.to_owned(),
+            url: format!("https://i.imgur.com/{}.jpg", id),
+            metadata,
+        };
+        let colors = ImageColors {
+            palette: vec![PaletteColor {
+                color: Lab::new(l, 0.0, 0.0),
+                weight: 1.0,
+            }],
+            layout: vec![Some(Lab::new(l, 0.0, 0.0)), None],
+            final_url: None,
+        };
+        IndexEntry::new(source, candidate, &colors)
+    }
+
+    fn gray(l: f32) -> Target {
+        Target::Palette(vec![PaletteColor {
+            color: Lab::new(l, 0.0, 0.0),
+            weight: 1.0,
+        }])
+    }
+
+    fn find_nearest<'a>(
+        index: &'a ColorIndex,
+        source: &str,
+        query: &str,
+        target: &Target,
+        limit: usize,
+    ) -> Vec<(f32, &'a IndexEntry)> {
+        index.nearest(&NearestQuery {
+            source: source.to_owned(),
+            query: query.to_owned(),
+            target: target.clone(),
+            limit,
+            weights: RankingWeights::default(),
+            filter: CandidateFilter::default(),
+        })
+    }
+
+    fn ids<'a>(nearest: &[(f32, &'a IndexEntry)]) -> Vec<&'a str> {
+        nearest.iter().map(|x| x.1.candidate.id.as_str()).collect()
+    }
+
+    #[quickcheck]
+    fn nearest_ranks_like_the_search(lightness: Vec<u8>, target: u8) -> bool {
+        let mut index = ColorIndex::new();
+        for (i, l) in lightness.iter().enumerate() {
+            index
+                .insert(entry("reddit", &i.to_string(), "gray", *l as f32))
+                .unwrap();
+        }
+        let target = gray(target as f32);
+        let mut expected: Vec<f32> = index
+            .entries
+            .iter()
+            .map(|x| target_distance(&target, &x.colors()))
+            .collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        expected.truncate(3);
+        let nearest: Vec<f32> = find_nearest(&index, "reddit", "", &target, 3)
+            .iter()
+            .map(|x| x.0)
+            .collect();
+        nearest == expected
+    }
+
+    #[test]
+    fn nearest_filters_source_and_query() {
+        let mut index = ColorIndex::new();
+        index
+            .insert(entry("reddit", "a", "Red Ferrari", 50.0))
+            .unwrap();
+        index
+            .insert(entry("reddit", "b", "Blue van", 50.0))
+            .unwrap();
+        index
+            .insert(entry("corpus", "c", "Red Ferrari", 50.0))
+            .unwrap();
+        index
+            .insert(entry("reddit", "d", "ferrari at night", 10.0))
+            .unwrap();
+        assert!(!index
+            .insert(entry("reddit", "a", "Red Ferrari", 90.0))
+            .unwrap());
+        assert_eq!(
+            ids(&find_nearest(&index, "reddit", "FERRARI", &gray(45.0), 10)),
+            vec!["a", "d"]
+        );
+        assert_eq!(
+            ids(&find_nearest(&index, "corpus", "", &gray(45.0), 10)),
+            vec!["c"]
+        );
+        let layout = Target::Layout(vec![None, None, Some(Lab::new(45.0, 0.0, 0.0))]);
+        assert_eq!(find_nearest(&index, "reddit", "van", &layout, 10).len(), 1);
+    }
+
+    #[test]
+    fn weights_trade_color_for_text() {
+        let mut index = ColorIndex::new();
+        index.insert(entry("reddit", "a", "Ferrari", 10.0)).unwrap();
+        index
+            .insert(entry("reddit", "b", "Ferrari at the track at night", 45.0))
+            .unwrap();
+        let mut query = NearestQuery {
+            source: "reddit".to_owned(),
+            query: "ferrari".to_owned(),
+            target: gray(45.0),
+            limit: 2,
+            weights: RankingWeights {
+                color: 1.0,
+                text: 0.0,
+            },
+            filter: CandidateFilter::default(),
+        };
+        assert_eq!(ids(&index.nearest(&query)), vec!["b", "a"]);
+        query.weights = RankingWeights {
+            color: 0.01,
+            text: 1.0,
+        };
+        assert_eq!(ids(&index.nearest(&query)), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn entries_are_read_back() {
+        let file = std::env::temp_dir().join(format!(
+            "search-api-index-{}/colors.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        let mut index = ColorIndex::open(file.clone()).unwrap();
+        index
+            .insert(entry("reddit", "a", "Red Ferrari", 50.0))
+            .unwrap();
+        index
+            .insert(entry("reddit", "b", "Blue van", 20.0))
+            .unwrap();
+
+        let index = ColorIndex::open(file.clone()).unwrap();
+        assert_eq!(index.len(), 2);
+        let nearest = find_nearest(&index, "reddit", "", &gray(25.0), 1);
+        assert_eq!(ids(&nearest), vec!["b"]);
+        assert_eq!(nearest[0].1.colors().layout.len(), 2);
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn stale_entries_are_replaced() {
+        let file = std::env::temp_dir().join(format!(
+            "search-api-stale-index-{}/colors.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        let mut index = ColorIndex::open(file.clone()).unwrap();
+        let stale = IndexEntry {
+            layout: vec![],
+            ..entry("reddit", "a", "Red Ferrari", 50.0)
+        };
+        assert!(index.insert(stale.clone()).unwrap());
+        let layout = Target::Layout(vec![Some(Lab::new(50.0, 0.0, 0.0))]);
+        assert!(find_nearest(&index, "reddit", "", &layout, 1).is_empty());
+        assert_eq!(
+            ids(&find_nearest(&index, "reddit", "", &gray(50.0), 1)),
+            vec!["a"]
+        );
+        assert!(!index.insert(stale).unwrap());
+
+        assert!(index
+            .insert(entry("reddit", "a", "Red Ferrari", 50.0))
+            .unwrap());
+        assert!(!index
+            .insert(entry("reddit", "a", "Red Ferrari", 50.0))
+            .unwrap());
+        assert_eq!(index.len(), 1);
+        let index = ColorIndex::open(file.clone()).unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(
+            ids(&find_nearest(&index, "reddit", "", &layout, 1)),
+            vec!["a"]
+        );
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn posts_without_the_nsfw_flag_are_replaced() {
+        let post = |over_18: Option<bool>| {
+            let mut entry = entry("reddit", "a", "Red Ferrari", 50.0);
+            let metadata = &mut entry.candidate.metadata;
+            metadata.insert("subreddit".to_owned(), Value::from("cars"));
+            if let Some(over_18) = over_18 {
+                metadata.insert("over_18".to_owned(), Value::from(over_18));
+            }
+            entry
+        };
+        let mut index = ColorIndex::new();
+        assert!(index.insert(post(None)).unwrap());
+        let mut query = NearestQuery {
+            source: "reddit".to_owned(),
+            query: "".to_owned(),
+            target: gray(50.0),
+            limit: 1,
+            weights: RankingWeights::default(),
+            filter: CandidateFilter {
+                nsfw: false,
+                ..CandidateFilter::default()
+            },
+        };
+        assert!(index.nearest(&query).is_empty());
+        assert!(index.insert(post(Some(false))).unwrap());
+        assert!(!index.insert(post(None)).unwrap());
+        assert_eq!(index.len(), 1);
+        assert_eq!(ids(&index.nearest(&query)), vec!["a"]);
+        query.filter = CandidateFilter::default();
+        assert_eq!(ids(&index.nearest(&query)), vec!["a"]);
+    }
+}
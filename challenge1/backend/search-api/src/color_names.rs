@@ -0,0 +1,334 @@
+use once_cell::sync::Lazy;
+use palette::Lab;
+use serde::Serialize;
+
+use crate::colors::{lab_distance, lab_to_hex, rgb8_to_lab, PaletteColor};
+
+// CSS Color Module Level 4 named colors (the X11 set plus rebeccapurple)
+pub const CSS_COLORS: &[(&str, u8, u8, u8)] = &[
+    ("aliceblue", 240, 248, 255),
+    ("antiquewhite", 250, 235, 215),
+    ("aqua", 0, 255, 255),
+    ("aquamarine", 127, 255, 212),
+    ("azure", 240, 255, 255),
+    ("beige", 245, 245, 220),
+    ("bisque", 255, 228, 196),
+    ("black", 0, 0, 0),
+    ("blanchedalmond", 255, 235, 205),
+    ("blue", 0, 0, 255),
+    ("blueviolet", 138, 43, 226),
+    ("brown", 165, 42, 42),
+    ("burlywood", 222, 184, 135),
+    ("cadetblue", 95, 158, 160),
+    ("chartreuse", 127, 255, 0),
+    ("chocolate", 210, 105, 30),
+    ("coral", 255, 127, 80),
+    ("cornflowerblue", 100, 149, 237),
+    ("cornsilk", 255, 248, 220),
+    ("crimson", 220, 20, 60),
+    ("cyan", 0, 255, 255),
+    ("darkblue", 0, 0, 139),
+    ("darkcyan", 0, 139, 139),
+    ("darkgoldenrod", 184, 134, 11),
+    ("darkgray", 169, 169, 169),
+    ("darkgreen", 0, 100, 0),
+    ("darkgrey", 169, 169, 169),
+    ("darkkhaki", 189, 183, 107),
+    ("darkmagenta", 139, 0, 139),
+    ("darkolivegreen", 85, 107, 47),
+    ("darkorange", 255, 140, 0),
+    ("darkorchid", 153, 50, 204),
+    ("darkred", 139, 0, 0),
+    ("darksalmon", 233, 150, 122),
+    ("darkseagreen", 143, 188, 143),
+    ("darkslateblue", 72, 61, 139),
+    ("darkslategray", 47, 79, 79),
+    ("darkslategrey", 47, 79, 79),
+    ("darkturquoise", 0, 206, 209),
+    ("darkviolet", 148, 0, 211),
+    ("deeppink", 255, 20, 147),
+    ("deepskyblue", 0, 191, 255),
+    ("dimgray", 105, 105, 105),
+    ("dimgrey", 105, 105, 105),
+    ("dodgerblue", 30, 144, 255),
+    ("firebrick", 178, 34, 34),
+    ("floralwhite", 255, 250, 240),
+    ("forestgreen", 34, 139, 34),
+    ("fuchsia", 255, 0, 255),
+    ("gainsboro", 220, 220, 220),
+    ("ghostwhite", 248, 248, 255),
+    ("gold", 255, 215, 0),
+    ("goldenrod", 218, 165, 32),
+    ("gray", 128, 128, 128),
+    ("green", 0, 128, 0),
+    ("greenyellow", 173, 255, 47),
+    ("grey", 128, 128, 128),
+    ("honeydew", 240, 255, 240),
+    ("hotpink", 255, 105, 180),
+    ("indianred", 205, 92, 92),
+    ("indigo", 75, 0, 130),
+    ("ivory", 255, 255, 240),
+    ("khaki", 240, 230, 140),
+    ("lavender", 230, 230, 250),
+    ("lavenderblush", 255, 240, 245),
+    ("lawngreen", 124, 252, 0),
+    ("lemonchiffon", 255, 250, 205),
+    ("lightblue", 173, 216, 230),
+    ("lightcoral", 240, 128, 128),
+    ("lightcyan", 224, 255, 255),
+    ("lightgoldenrodyellow", 250, 250, 210),
+    ("lightgray", 211, 211, 211),
+    ("lightgreen", 144, 238, 144),
+    ("lightgrey", 211, 211, 211),
+    ("lightpink", 255, 182, 193),
+    ("lightsalmon", 255, 160, 122),
+    ("lightseagreen", 32, 178, 170),
+    ("lightskyblue", 135, 206, 250),
+    ("lightslategray", 119, 136, 153),
+    ("lightslategrey", 119, 136, 153),
+    ("lightsteelblue", 176, 196, 222),
+    ("lightyellow", 255, 255, 224),
+    ("lime", 0, 255, 0),
+    ("limegreen", 50, 205, 50),
+    ("linen", 250, 240, 230),
+    ("magenta", 255, 0, 255),
+    ("maroon", 128, 0, 0),
+    ("mediumaquamarine", 102, 205, 170),
+    ("mediumblue", 0, 0, 205),
+    ("mediumorchid", 186, 85, 211),
+    ("mediumpurple", 147, 112, 219),
+    ("mediumseagreen", 60, 179, 113),
+    ("mediumslateblue", 123, 104, 238),
+    ("mediumspringgreen", 0, 250, 154),
+    ("mediumturquoise", 72, 209, 204),
+    ("mediumvioletred", 199, 21, 133),
+    ("midnightblue", 25, 25, 112),
+    ("mintcream", 245, 255, 250),
+    ("mistyrose", 255, 228, 225),
+    ("moccasin", 255, 228, 181),
+    ("navajowhite", 255, 222, 173),
+    ("navy", 0, 0, 128),
+    ("oldlace", 253, 245, 230),
+    ("olive", 128, 128, 0),
+    ("olivedrab", 107, 142, 35),
+    ("orange", 255, 165, 0),
+    ("orangered", 255, 69, 0),
+    ("orchid", 218, 112, 214),
+    ("palegoldenrod", 238, 232, 170),
+    ("palegreen", 152, 251, 152),
+    ("paleturquoise", 175, 238, 238),
+    ("palevioletred", 219, 112, 147),
+    ("papayawhip", 255, 239, 213),
+    ("peachpuff", 255, 218, 185),
+    ("peru", 205, 133, 63),
+    ("pink", 255, 192, 203),
+    ("plum", 221, 160, 221),
+    ("powderblue", 176, 224, 230),
+    ("purple", 128, 0, 128),
+    ("rebeccapurple", 102, 51, 153),
+    ("red", 255, 0, 0),
+    ("rosybrown", 188, 143, 143),
+    ("royalblue", 65, 105, 225),
+    ("saddlebrown", 139, 69, 19),
+    ("salmon", 250, 128, 114),
+    ("sandybrown", 244, 164, 96),
+    ("seagreen", 46, 139, 87),
+    ("seashell", 255, 245, 238),
+    ("sienna", 160, 82, 45),
+    ("silver", 192, 192, 192),
+    ("skyblue", 135, 206, 235),
+    ("slateblue", 106, 90, 205),
+    ("slategray", 112, 128, 144),
+    ("slategrey", 112, 128, 144),
+    ("snow", 255, 250, 250),
+    ("springgreen", 0, 255, 127),
+    ("steelblue", 70, 130, 180),
+    ("tan", 210, 180, 140),
+    ("teal", 0, 128, 128),
+    ("thistle", 216, 191, 216),
+    ("tomato", 255, 99, 71),
+    ("turquoise", 64, 224, 208),
+    ("violet", 238, 130, 238),
+    ("wheat", 245, 222, 179),
+    ("white", 255, 255, 255),
+    ("whitesmoke", 245, 245, 245),
+    ("yellow", 255, 255, 0),
+    ("yellowgreen", 154, 205, 50),
+];
+
+// A curated subset of the XKCD color survey names, leaving out the ones
+// already taken by a CSS color so every name maps to a single color
+pub const XKCD_COLORS: &[(&str, u8, u8, u8)] = &[
+    ("light purple", 191, 119, 246),
+    ("mauve", 174, 113, 129),
+    ("dark purple", 53, 6, 62),
+    ("bright green", 1, 255, 7),
+    ("navy blue", 0, 17, 70),
+    ("lilac", 206, 162, 253),
+    ("light brown", 173, 129, 80),
+    ("peach", 255, 176, 124),
+    ("olive green", 103, 122, 4),
+    ("dark pink", 203, 65, 107),
+    ("periwinkle", 142, 130, 254),
+    ("mustard", 206, 179, 1),
+    ("rose", 207, 98, 117),
+    ("bright blue", 1, 101, 252),
+    ("neon green", 12, 255, 12),
+    ("burnt orange", 192, 78, 1),
+    ("grass green", 63, 155, 11),
+    ("pale blue", 208, 254, 254),
+    ("bright purple", 190, 3, 253),
+    ("baby blue", 162, 207, 254),
+    ("mint green", 143, 255, 159),
+    ("royal purple", 75, 0, 110),
+    ("brick red", 143, 20, 2),
+    ("dark teal", 1, 77, 78),
+    ("burgundy", 97, 0, 35),
+    ("blue green", 19, 126, 109),
+    ("seafoam green", 122, 249, 171),
+    ("kelly green", 2, 171, 46),
+    ("pea green", 142, 171, 18),
+    ("taupe", 185, 162, 129),
+    ("dark brown", 52, 28, 2),
+    ("deep purple", 54, 1, 63),
+    ("bright pink", 254, 1, 177),
+    ("light orange", 253, 170, 72),
+    ("mint", 159, 254, 176),
+    ("pastel green", 176, 255, 157),
+    ("sand", 226, 202, 118),
+    ("puce", 165, 126, 82),
+    ("seafoam", 128, 249, 173),
+    ("grey blue", 107, 139, 164),
+    ("army green", 75, 93, 22),
+    ("dark yellow", 213, 182, 10),
+    ("slate", 81, 101, 114),
+    ("light teal", 144, 228, 193),
+    ("rust", 168, 60, 9),
+    ("deep blue", 4, 2, 115),
+    ("pale pink", 255, 207, 220),
+    ("cerulean", 4, 133, 209),
+    ("light red", 255, 71, 76),
+    ("mustard yellow", 210, 189, 10),
+    ("ochre", 191, 144, 5),
+    ("pale yellow", 255, 255, 132),
+    ("hunter green", 11, 64, 8),
+    ("blue grey", 96, 124, 142),
+    ("pale purple", 183, 144, 212),
+    ("sea blue", 4, 116, 149),
+    ("leaf green", 92, 169, 4),
+    ("eggplant", 56, 8, 53),
+    ("moss green", 101, 139, 56),
+    ("grey green", 120, 155, 115),
+    ("sage", 135, 174, 115),
+    ("brick", 160, 54, 35),
+    ("burnt sienna", 176, 78, 15),
+    ("reddish brown", 127, 43, 10),
+    ("cream", 255, 255, 194),
+    ("ocean blue", 3, 113, 156),
+    ("red orange", 253, 60, 6),
+    ("bluish purple", 112, 59, 231),
+    ("light violet", 214, 180, 252),
+    ("dusty rose", 192, 115, 122),
+    ("greenish yellow", 205, 253, 2),
+    ("yellowish green", 176, 221, 22),
+    ("purplish blue", 96, 30, 249),
+    ("greyish blue", 94, 129, 157),
+    ("grape", 108, 52, 97),
+    ("light olive", 172, 191, 105),
+    ("cornflower", 106, 121, 247),
+    ("off white", 255, 255, 228),
+    ("charcoal", 52, 56, 55),
+    ("terracotta", 202, 102, 65),
+    ("sky", 130, 202, 252),
+    ("wine", 128, 1, 63),
+    ("denim blue", 59, 91, 146),
+    ("emerald", 1, 160, 73),
+    ("dark olive", 55, 62, 2),
+];
+
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-' && *c != '_')
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+fn all_named_colors() -> impl Iterator<Item = &'static (&'static str, u8, u8, u8)> {
+    CSS_COLORS.iter().chain(XKCD_COLORS.iter())
+}
+
+pub fn find_named_color(name: &str) -> Option<Lab> {
+    let name = normalize_name(name);
+    all_named_colors()
+        .find(|(color_name, _, _, _)| normalize_name(color_name) == name)
+        .map(|(_, r, g, b)| rgb8_to_lab(*r, *g, *b))
+}
+
+// Every named color in Lab, converted once
+static NAMED_LABS: Lazy<Vec<(&'static str, Lab)>> = Lazy::new(|| {
+    all_named_colors()
+        .map(|(name, r, g, b)| (*name, rgb8_to_lab(*r, *g, *b)))
+        .collect()
+});
+
+pub fn get_color_name(color: &Lab) -> &'static str {
+    let mut best = ("", f32::MAX);
+    for (name, lab) in NAMED_LABS.iter() {
+        let distance = lab_distance(color, lab);
+        if distance < best.1 {
+            best = (*name, distance);
+        }
+    }
+    best.0
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ColorDescription {
+    pub name: &'static str,
+    pub hex: String,
+    pub weight: f32,
+}
+
+pub fn describe_palette(palette: &[PaletteColor]) -> Vec<ColorDescription> {
+    palette
+        .iter()
+        .map(|x| ColorDescription {
+            name: get_color_name(&x.color),
+            hex: lab_to_hex(&x.color),
+            weight: x.weight,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[quickcheck]
+    fn find_named_color_ignores_case(i: usize) -> bool {
+        let (name, _, _, _) = CSS_COLORS[i % CSS_COLORS.len()];
+        find_named_color(&name.to_uppercase()) == find_named_color(name)
+            && find_named_color(name).is_some()
+    }
+
+    #[quickcheck]
+    fn get_color_name_of_named_color_is_itself(i: usize) -> bool {
+        let colors: Vec<_> = all_named_colors().collect();
+        let (name, r, g, b) = *colors[i % colors.len()];
+        let color = rgb8_to_lab(r, g, b);
+        lab_distance(&find_named_color(get_color_name(&color)).unwrap(), &color) < 0.01
+            && find_named_color(name).is_some()
+    }
+
+    #[test]
+    fn find_named_color_ignores_separators() {
+        assert_eq!(
+            find_named_color("rebecca purple"),
+            find_named_color("rebeccapurple")
+        );
+        assert_eq!(
+            find_named_color("Dark-Slate_Gray"),
+            find_named_color("darkslategray")
+        );
+        assert_eq!(find_named_color("dark teal"), Some(rgb8_to_lab(1, 77, 78)));
+    }
+}
@@ -0,0 +1,345 @@
+use palette::{IntoColor, Lab, Srgb};
+use thiserror::Error;
+
+use crate::color_names::find_named_color;
+use crate::colors::{
+    normalize_weights, rgb8_to_lab, Layout, Palette, PaletteColor, LAYOUT_SIZE, MAX_PALETTE_COLORS,
+};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ErrorCode {
+    #[error("{0} must be a number")]
+    NotANumber(&'static str),
+    #[error("{name} must be between {min} and {max}")]
+    OutOfRange {
+        name: &'static str,
+        min: f32,
+        max: f32,
+    },
+    #[error("{name} expects {expected} comma separated values")]
+    WrongComponentCount { name: &'static str, expected: usize },
+    #[error("invalid hex color \"{0}\", expected #rgb or #rrggbb")]
+    InvalidHex(String),
+    #[error("unknown color \"{0}\"")]
+    UnknownColor(String),
+    #[error("palette must have between 1 and {0} colors")]
+    InvalidPaletteSize(usize),
+    #[error("weight must be a positive number")]
+    InvalidWeight,
+    #[error("layout must have {0} cells separated by \";\", use * for any color")]
+    InvalidLayoutSize(usize),
+    #[error("layout must have at least one color")]
+    EmptyLayout,
+    #[error("missing color, use one of color, hsl, lab, palette, layout or r, g and b")]
+    MissingColor,
+    #[error("only one of color, hsl, lab, palette, layout or r, g and b can be used")]
+    AmbiguousColor,
+}
+
+fn check_range(name: &'static str, v: f32, min: f32, max: f32) -> Result<f32, ErrorCode> {
+    if v.is_finite() && (min..=max).contains(&v) {
+        Ok(v)
+    } else {
+        Err(ErrorCode::OutOfRange { name, min, max })
+    }
+}
+
+fn parse_component(name: &'static str, s: &str, min: f32, max: f32) -> Result<f32, ErrorCode> {
+    let v = s
+        .trim()
+        .parse::<f32>()
+        .or(Err(ErrorCode::NotANumber(name)))?;
+    check_range(name, v, min, max)
+}
+
+fn parse_components(
+    name: &'static str,
+    s: &str,
+    names: [&'static str; 3],
+    ranges: [(f32, f32); 3],
+) -> Result<(f32, f32, f32), ErrorCode> {
+    let parts: Vec<&str> = s.split(',').collect();
+    if parts.len() != 3 {
+        return Err(ErrorCode::WrongComponentCount { name, expected: 3 });
+    }
+    let mut values = [0.0f32; 3];
+    for (i, part) in parts.iter().enumerate() {
+        let part = part.trim().trim_end_matches('%');
+        values[i] = parse_component(names[i], part, ranges[i].0, ranges[i].1)?;
+    }
+    Ok((values[0], values[1], values[2]))
+}
+
+pub fn parse_rgb(r: &str, g: &str, b: &str) -> Result<Lab, ErrorCode> {
+    let r = parse_component("r", r, 0.0, 1.0)?;
+    let g = parse_component("g", g, 0.0, 1.0)?;
+    let b = parse_component("b", b, 0.0, 1.0)?;
+    Ok(Srgb::new(r, g, b).into_lab())
+}
+
+pub fn parse_hex(s: &str) -> Result<Lab, ErrorCode> {
+    let hex = s.trim_start_matches('#');
+    let invalid = || ErrorCode::InvalidHex(s.to_owned());
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let digits: Vec<u8> = hex
+        .chars()
+        .map(|c| c.to_digit(16).unwrap_or(0) as u8)
+        .collect();
+    match digits.as_slice() {
+        [r, g, b] => Ok(rgb8_to_lab(r * 17, g * 17, b * 17)),
+        [r1, r2, g1, g2, b1, b2] => Ok(rgb8_to_lab(r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2)),
+        _ => Err(invalid()),
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.starts_with('#')
+        || ((s.len() == 3 || s.len() == 6) && s.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// "#ff8800", "ff8800", "#f80" or a CSS color name
+pub fn parse_color(s: &str) -> Result<Lab, ErrorCode> {
+    let s = s.trim();
+    if is_hex(s) {
+        parse_hex(s)
+    } else {
+        find_named_color(s).ok_or_else(|| ErrorCode::UnknownColor(s.to_owned()))
+    }
+}
+
+fn hsl_to_srgb(h: f32, s: f32, l: f32) -> Srgb {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let h = (h % 360.0) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    Srgb::new(r + m, g + m, b + m)
+}
+
+// "h,s,l" with hue in degrees and saturation/lightness in percent
+pub fn parse_hsl(s: &str) -> Result<Lab, ErrorCode> {
+    let (h, s, l) = parse_components(
+        "hsl",
+        s,
+        ["hue", "saturation", "lightness"],
+        [(0.0, 360.0), (0.0, 100.0), (0.0, 100.0)],
+    )?;
+    Ok(hsl_to_srgb(h, s / 100.0, l / 100.0).into_lab())
+}
+
+// "l,a,b" in CIE L*a*b* units
+pub fn parse_lab(s: &str) -> Result<Lab, ErrorCode> {
+    let (l, a, b) = parse_components(
+        "lab",
+        s,
+        ["l", "a", "b"],
+        [(0.0, 100.0), (-128.0, 127.0), (-128.0, 127.0)],
+    )?;
+    Ok(Lab::new(l, a, b))
+}
+
+fn parse_weight(s: &str) -> Result<f32, ErrorCode> {
+    match s.trim().parse::<f32>() {
+        Ok(weight) if weight.is_finite() && weight > 0.0 => Ok(weight),
+        _ => Err(ErrorCode::InvalidWeight),
+    }
+}
+
+// "r,g,b[,weight]" with components in 0..1, or "color[,weight]" where color
+// is anything parse_color understands.
+fn parse_palette_color(s: &str) -> Result<PaletteColor, ErrorCode> {
+    let parts: Vec<&str> = s.split(',').collect();
+    let (color, weight) = match parts.as_slice() {
+        [color] => (parse_color(color)?, 1.0),
+        [color, weight] => (parse_color(color)?, parse_weight(weight)?),
+        [r, g, b] => (parse_rgb(r, g, b)?, 1.0),
+        [r, g, b, weight] => (parse_rgb(r, g, b)?, parse_weight(weight)?),
+        _ => {
+            return Err(ErrorCode::WrongComponentCount {
+                name: "palette color",
+                expected: 4,
+            })
+        }
+    };
+    Ok(PaletteColor { color, weight })
+}
+
+// palette colors separated by ";", e.g. "navy;orange,0.5"
+pub fn parse_palette(s: &str) -> Result<Palette, ErrorCode> {
+    let palette = s
+        .split(';')
+        .filter(|x| !x.trim().is_empty())
+        .map(parse_palette_color)
+        .collect::<Result<Palette, ErrorCode>>()?;
+    if palette.is_empty() || palette.len() > MAX_PALETTE_COLORS {
+        return Err(ErrorCode::InvalidPaletteSize(MAX_PALETTE_COLORS));
+    }
+    Ok(normalize_weights(palette))
+}
+
+// LAYOUT_SIZE x LAYOUT_SIZE cells, row by row, separated by ";". Each cell is
+// anything parse_color understands, or "*" for any color.
+// e.g. "skyblue;skyblue;skyblue;*;*;*;green;green;green"
+pub fn parse_layout(s: &str) -> Result<Layout, ErrorCode> {
+    let layout = s
+        .split(';')
+        .map(|x| match x.trim() {
+            "*" | "" => Ok(None),
+            color => parse_color(color).map(Some),
+        })
+        .collect::<Result<Layout, ErrorCode>>()?;
+    if layout.len() != LAYOUT_SIZE * LAYOUT_SIZE {
+        return Err(ErrorCode::InvalidLayoutSize(LAYOUT_SIZE * LAYOUT_SIZE));
+    }
+    if layout.iter().all(|x| x.is_none()) {
+        return Err(ErrorCode::EmptyLayout);
+    }
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::colors::lab_distance;
+    use float_eq::float_eq;
+
+    fn same_color(a: &Lab, b: &Lab) -> bool {
+        lab_distance(a, b) < 0.01
+    }
+
+    #[quickcheck]
+    fn parse_color_accepts_any_hex(r: u8, g: u8, b: u8) -> bool {
+        let lab = rgb8_to_lab(r, g, b);
+        let long = format!("#{:02x}{:02X}{:02x}", r, g, b);
+        let without_hash = format!("{:02x}{:02x}{:02x}", r, g, b);
+        same_color(&parse_color(&long).unwrap(), &lab)
+            && same_color(&parse_color(&without_hash).unwrap(), &lab)
+    }
+
+    #[quickcheck]
+    fn parse_color_short_hex_doubles_digits(r: u8, g: u8, b: u8) -> bool {
+        let (r, g, b) = (r % 16, g % 16, b % 16);
+        let short = format!("#{:x}{:x}{:x}", r, g, b);
+        same_color(
+            &parse_color(&short).unwrap(),
+            &rgb8_to_lab(r * 17, g * 17, b * 17),
+        )
+    }
+
+    #[quickcheck]
+    fn parse_color_never_panics(s: String) -> bool {
+        let _ = parse_color(&s);
+        let _ = parse_hsl(&s);
+        let _ = parse_lab(&s);
+        let _ = parse_palette(&s);
+        let _ = parse_layout(&s);
+        true
+    }
+
+    #[quickcheck]
+    fn parse_rgb_accepts_only_zero_to_one(r: f32, g: f32, b: f32) -> bool {
+        let in_range = |x: f32| (0.0..=1.0).contains(&x);
+        let result = parse_rgb(&r.to_string(), &g.to_string(), &b.to_string());
+        result.is_ok() == (in_range(r) && in_range(g) && in_range(b))
+    }
+
+    #[quickcheck]
+    fn parse_hsl_accepts_only_valid_ranges(h: f32, s: f32, l: f32) -> bool {
+        let valid =
+            (0.0..=360.0).contains(&h) && (0.0..=100.0).contains(&s) && (0.0..=100.0).contains(&l);
+        parse_hsl(&format!("{},{}%,{}%", h, s, l)).is_ok() == valid
+    }
+
+    #[quickcheck]
+    fn parse_hsl_grays_have_no_hue(h: u16, l: u8) -> bool {
+        let l = l % 101;
+        let gray = parse_hsl(&format!("{},0,{}", h % 361, l)).unwrap();
+        float_eq!(gray.a, 0.0, abs <= 0.1) && float_eq!(gray.b, 0.0, abs <= 0.1)
+    }
+
+    #[quickcheck]
+    fn parse_lab_roundtrips(l: u8, a: i8, b: i8) -> bool {
+        let l = (l % 101) as f32;
+        let lab = parse_lab(&format!("{},{},{}", l, a, b)).unwrap();
+        float_eq!(lab.l, l, abs <= 0.0001)
+            && float_eq!(lab.a, a as f32, abs <= 0.0001)
+            && float_eq!(lab.b, b as f32, abs <= 0.0001)
+    }
+
+    #[quickcheck]
+    fn parse_palette_weights_sum_to_one(colors: Vec<(u8, u8, u8, u8)>) -> bool {
+        let s = colors
+            .iter()
+            .take(MAX_PALETTE_COLORS)
+            .map(|(r, g, b, w)| format!("#{:02x}{:02x}{:02x},{}", r, g, b, *w as f32 + 1.0))
+            .collect::<Vec<_>>()
+            .join(";");
+        match parse_palette(&s) {
+            Err(_) => colors.is_empty(),
+            Ok(palette) => {
+                let total: f32 = palette.iter().map(|x| x.weight).sum();
+                float_eq!(total, 1.0, abs <= 0.001)
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn parse_palette_rejects_more_than_max_colors(extra: u8) -> bool {
+        let s = vec!["0.5,0.5,0.5"; MAX_PALETTE_COLORS + 1 + extra as usize % 5].join(";");
+        parse_palette(&s) == Err(ErrorCode::InvalidPaletteSize(MAX_PALETTE_COLORS))
+    }
+
+    #[quickcheck]
+    fn parse_layout_keeps_cell_order(cells: Vec<Option<(u8, u8, u8)>>) -> bool {
+        let cells: Vec<Option<(u8, u8, u8)>> = cells
+            .into_iter()
+            .chain(std::iter::repeat(None))
+            .take(LAYOUT_SIZE * LAYOUT_SIZE)
+            .collect();
+        let s = cells
+            .iter()
+            .map(|x| match x {
+                None => "*".to_owned(),
+                Some((r, g, b)) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+        match parse_layout(&s) {
+            Err(err) => err == ErrorCode::EmptyLayout && cells.iter().all(|x| x.is_none()),
+            Ok(layout) => layout.iter().zip(cells.iter()).all(|(a, b)| match (a, b) {
+                (None, None) => true,
+                (Some(a), Some((r, g, b))) => same_color(a, &rgb8_to_lab(*r, *g, *b)),
+                _ => false,
+            }),
+        }
+    }
+
+    #[test]
+    fn parse_color_understands_names() {
+        assert_eq!(
+            parse_color("rebeccapurple"),
+            Ok(rgb8_to_lab(0x66, 0x33, 0x99))
+        );
+        assert_eq!(
+            parse_color("notacolor"),
+            Err(ErrorCode::UnknownColor("notacolor".to_owned()))
+        );
+    }
+
+    #[test]
+    fn parse_rgb_explains_out_of_range() {
+        assert_eq!(
+            parse_rgb("300", "0", "0").unwrap_err().to_string(),
+            "r must be between 0 and 1"
+        );
+    }
+}
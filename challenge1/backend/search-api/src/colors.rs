@@ -1,4 +1,50 @@
-use palette::Lab;
+use palette::{IntoColor, Lab, Srgb};
+
+pub const MAX_PALETTE_COLORS: usize = 5;
+pub const LAYOUT_SIZE: usize = 3;
+const LAYOUT_MISSING_CELL_DISTANCE: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PaletteColor {
+    pub color: Lab,
+    pub weight: f32,
+}
+
+pub type Palette = Vec<PaletteColor>;
+
+// Dominant color of each cell of a LAYOUT_SIZE x LAYOUT_SIZE grid, row by row
+pub type Layout = Vec<Option<Lab>>;
+
+#[derive(Clone, Debug, Default)]
+pub struct ImageColors {
+    pub palette: Palette,
+    pub layout: Layout,
+    // where the image was downloaded from, after following redirects
+    pub final_url: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Target {
+    Palette(Palette),
+    // cells without a color match anything
+    Layout(Layout),
+}
+
+impl ImageColors {
+    // Colors cached before layouts existed have an empty one
+    pub fn has_layout(&self) -> bool {
+        !self.layout.is_empty()
+    }
+
+    // Whether the distance to the target can be trusted, or the image must
+    // be analyzed again
+    pub fn can_compare(&self, target: &Target) -> bool {
+        match target {
+            Target::Palette(_) => true,
+            Target::Layout(_) => self.has_layout(),
+        }
+    }
+}
 
 pub fn lab_distance(a: &Lab, b: &Lab) -> f32 {
     let x = a.l - b.l;
@@ -7,6 +53,104 @@ pub fn lab_distance(a: &Lab, b: &Lab) -> f32 {
     (x * x + y * y + z * z).sqrt()
 }
 
+pub fn rgb8_to_lab(r: u8, g: u8, b: u8) -> Lab {
+    Srgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0).into_lab()
+}
+
+pub fn lab_to_hex(color: &Lab) -> String {
+    let rgb = Srgb::from(color.into_xyz());
+    let to_u8 = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        to_u8(rgb.red),
+        to_u8(rgb.green),
+        to_u8(rgb.blue)
+    )
+}
+
+pub fn normalize_weights(mut palette: Palette) -> Palette {
+    let total: f32 = palette.iter().map(|x| x.weight).sum();
+    if total > 0.0 {
+        for item in palette.iter_mut() {
+            item.weight /= total;
+        }
+    }
+    palette
+}
+
+// Weights are the share of the palette each color represents, so matching
+// a color that covers 60% of the query against one that covers 5% of the image
+// is penalized even if both colors are the same.
+fn palette_color_cost(query: &PaletteColor, image: &PaletteColor) -> f32 {
+    let weight_difference = (query.weight - image.weight).abs();
+    query.weight * (lab_distance(&query.color, &image.color) + 100.0 * weight_difference)
+}
+
+fn palette_assignment_cost(
+    query: &[PaletteColor],
+    image: &[PaletteColor],
+    used: &mut [bool],
+) -> f32 {
+    match query.split_first() {
+        None => 0.0,
+        Some((color, rest)) => {
+            // when the query has more colors than the image, colors can be reused
+            let all_used = used.iter().all(|x| *x);
+            let mut best = f32::MAX;
+            for (i, image_color) in image.iter().enumerate() {
+                if used[i] && !all_used {
+                    continue;
+                }
+                let was_used = used[i];
+                used[i] = true;
+                let cost = palette_color_cost(color, image_color)
+                    + palette_assignment_cost(rest, image, used);
+                used[i] = was_used;
+                if cost < best {
+                    best = cost;
+                }
+            }
+            best
+        }
+    }
+}
+
+// Optimal assignment between query and image colors. Palettes have at most
+// MAX_PALETTE_COLORS entries, so trying every assignment is cheap enough.
+pub fn palette_distance(query: &[PaletteColor], image: &[PaletteColor]) -> f32 {
+    if image.is_empty() {
+        return f32::MAX;
+    }
+    let mut used = vec![false; image.len()];
+    palette_assignment_cost(query, image, &mut used)
+}
+
+pub fn layout_distance(query: &[Option<Lab>], image: &[Option<Lab>]) -> f32 {
+    let mut total = 0.0;
+    let mut cells = 0;
+    for (i, color) in query.iter().enumerate() {
+        if let Some(color) = color {
+            total += match image.get(i) {
+                Some(Some(image_color)) => lab_distance(color, image_color),
+                _ => LAYOUT_MISSING_CELL_DISTANCE,
+            };
+            cells += 1;
+        }
+    }
+    if cells == 0 {
+        0.0
+    } else {
+        total / cells as f32
+    }
+}
+
+pub fn target_distance(target: &Target, image: &ImageColors) -> f32 {
+    match target {
+        Target::Palette(palette) => palette_distance(palette, &image.palette),
+        Target::Layout(layout) => layout_distance(layout, &image.layout),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -40,4 +184,54 @@ mod test {
             abs <= 0.000_000_1
         )
     }
+
+    fn to_palette(colors: &[(u8, u8, u8, u8)]) -> Palette {
+        let palette = colors
+            .iter()
+            .take(MAX_PALETTE_COLORS)
+            .map(|(r, g, b, w)| PaletteColor {
+                color: rgb8_to_lab(*r, *g, *b),
+                weight: *w as f32 + 1.0,
+            })
+            .collect();
+        normalize_weights(palette)
+    }
+
+    #[quickcheck]
+    fn lab_to_hex_roundtrips_rgb8(r: u8, g: u8, b: u8) -> bool {
+        lab_to_hex(&rgb8_to_lab(r, g, b)) == format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    #[quickcheck]
+    fn palette_distance_same_palette_must_return_zero(colors: Vec<(u8, u8, u8, u8)>) -> bool {
+        let palette = to_palette(&colors);
+        palette.is_empty() || float_eq!(palette_distance(&palette, &palette), 0.0, abs <= 0.001)
+    }
+
+    #[quickcheck]
+    fn palette_distance_never_negative(a: Vec<(u8, u8, u8, u8)>, b: Vec<(u8, u8, u8, u8)>) -> bool {
+        palette_distance(&to_palette(&a), &to_palette(&b)) >= 0.0
+    }
+
+    fn to_layout(colors: &[Option<(u8, u8, u8)>]) -> Layout {
+        colors
+            .iter()
+            .take(LAYOUT_SIZE * LAYOUT_SIZE)
+            .map(|x| x.map(|(r, g, b)| rgb8_to_lab(r, g, b)))
+            .collect()
+    }
+
+    #[quickcheck]
+    fn layout_distance_same_layout_must_return_zero(colors: Vec<Option<(u8, u8, u8)>>) -> bool {
+        let layout = to_layout(&colors);
+        float_eq!(layout_distance(&layout, &layout), 0.0, abs <= 0.001)
+    }
+
+    #[quickcheck]
+    fn layout_distance_never_negative(
+        a: Vec<Option<(u8, u8, u8)>>,
+        b: Vec<Option<(u8, u8, u8)>>,
+    ) -> bool {
+        layout_distance(&to_layout(&a), &to_layout(&b)) >= 0.0
+    }
 }
@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::actors::crawler::CrawlerOptions;
+use crate::fetch_policy::FetchPolicy;
+use crate::http::FetchOptions;
+use crate::reddit_api::RedditOptions;
+use crate::sampling::{parse_filter, SamplingOptions};
+
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .map(|x| {
+            x.split(',')
+                .map(|x| x.trim().to_owned())
+                .filter(|x| !x.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn env_opt(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|x| !x.is_empty())
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|x| x.parse::<T>().ok())
+        .unwrap_or(default)
+}
+
+pub struct Config {
+    pub sampling: SamplingOptions,
+    pub fetch: FetchOptions,
+    pub fetch_policy: FetchPolicy,
+    pub reddit: RedditOptions,
+    // searched by the "local" source
+    pub local_dir: Option<PathBuf>,
+    // source of searches that do not choose one
+    pub default_source: String,
+    // imported reddit posts, searched by the "corpus" source
+    pub corpus: PathBuf,
+    // colors of every analyzed candidate, answering searches before any source
+    pub color_index: PathBuf,
+    pub crawler: CrawlerOptions,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let default = SamplingOptions::default();
+        let sampling = SamplingOptions {
+            size: env_or("SEARCH_API_SAMPLE_SIZE", default.size),
+            filter: std::env::var("SEARCH_API_SAMPLE_FILTER")
+                .ok()
+                .and_then(|x| parse_filter(&x))
+                .unwrap_or(default.filter),
+            ignore_transparent: env_or("SEARCH_API_IGNORE_TRANSPARENT", default.ignore_transparent),
+            exclude_borders: env_or("SEARCH_API_EXCLUDE_BORDERS", default.exclude_borders),
+            weighting: env_or("SEARCH_API_WEIGHTING", default.weighting),
+            frames: env_or("SEARCH_API_ANIMATION_FRAMES", default.frames),
+            max_decoded_frames: env_or("SEARCH_API_MAX_DECODED_FRAMES", default.max_decoded_frames),
+            max_decoded_bytes: env_or("SEARCH_API_MAX_DECODED_BYTES", default.max_decoded_bytes),
+            fast_jpeg: env_or("SEARCH_API_FAST_JPEG", default.fast_jpeg),
+        };
+        let default = FetchOptions::default();
+        let fetch = FetchOptions {
+            max_redirects: env_or("SEARCH_API_MAX_REDIRECTS", default.max_redirects),
+            connect_timeout: Duration::from_millis(env_or(
+                "SEARCH_API_CONNECT_TIMEOUT_MS",
+                default.connect_timeout.as_millis() as u64,
+            )),
+            timeout: Duration::from_millis(env_or(
+                "SEARCH_API_TIMEOUT_MS",
+                default.timeout.as_millis() as u64,
+            )),
+            max_response_bytes: env_or("SEARCH_API_MAX_RESPONSE_BYTES", default.max_response_bytes),
+            max_connections_per_host: env_or(
+                "SEARCH_API_MAX_CONNECTIONS_PER_HOST",
+                default.max_connections_per_host,
+            ),
+            max_attempts: env_or("SEARCH_API_MAX_ATTEMPTS", default.max_attempts),
+            retry_base_delay: Duration::from_millis(env_or(
+                "SEARCH_API_RETRY_BASE_DELAY_MS",
+                default.retry_base_delay.as_millis() as u64,
+            )),
+            retry_max_delay: Duration::from_millis(env_or(
+                "SEARCH_API_RETRY_MAX_DELAY_MS",
+                default.retry_max_delay.as_millis() as u64,
+            )),
+        };
+        let fetch_policy = FetchPolicy {
+            allow_hosts: env_list("SEARCH_API_ALLOW_HOSTS"),
+            deny_hosts: env_list("SEARCH_API_DENY_HOSTS"),
+        };
+        let default = RedditOptions::default();
+        let reddit = RedditOptions {
+            client_id: env_opt("SEARCH_API_REDDIT_CLIENT_ID"),
+            client_secret: env_opt("SEARCH_API_REDDIT_CLIENT_SECRET"),
+            token_url: env_or("SEARCH_API_REDDIT_TOKEN_URL", default.token_url),
+            oauth_url: env_or("SEARCH_API_REDDIT_OAUTH_URL", default.oauth_url),
+            public_url: env_or("SEARCH_API_REDDIT_URL", default.public_url),
+        };
+        let default = CrawlerOptions::default();
+        let crawler = CrawlerOptions {
+            queries: env_list("SEARCH_API_CRAWL_QUERIES"),
+            subreddits: env_list("SEARCH_API_CRAWL_SUBREDDITS"),
+            source: env_opt("SEARCH_API_CRAWL_SOURCE"),
+            interval: Duration::from_millis(env_or(
+                "SEARCH_API_CRAWL_INTERVAL_MS",
+                default.interval.as_millis() as u64,
+            )),
+            max_candidates: env_or("SEARCH_API_CRAWL_MAX_CANDIDATES", default.max_candidates),
+        };
+        Config {
+            sampling,
+            fetch,
+            fetch_policy,
+            reddit,
+            local_dir: env_opt("SEARCH_API_LOCAL_DIR").map(PathBuf::from),
+            default_source: env_or("SEARCH_API_DEFAULT_SOURCE", "reddit".to_owned()),
+            corpus: PathBuf::from(env_or(
+                "SEARCH_API_CORPUS",
+                ".corpus/posts.jsonl".to_owned(),
+            )),
+            color_index: PathBuf::from(env_or(
+                "SEARCH_API_COLOR_INDEX",
+                ".index/colors.jsonl".to_owned(),
+            )),
+            crawler,
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::http::OnFetchEvent;
+use crate::image_source::{self, Candidate, ImageSource, Listing, Page};
+use crate::query::parse_query;
+use crate::reddit::{
+    get_candidate, is_image, RedditResultDataChildren, RedditResultDataChildrenData,
+};
+use crate::text::get_words;
+
+const PAGE_SIZE: usize = 100;
+
+// Pushshift dumps are compressed with windows of up to 2GiB
+const ZSTD_WINDOW_LOG_MAX: u32 = 31;
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportStats {
+    pub lines: u64,
+    pub imported: u64,
+    pub duplicates: u64,
+    pub not_images: u64,
+    pub invalid: u64,
+}
+
+pub fn read_dump<'a>(reader: impl Read + 'a, zstd: bool) -> io::Result<Box<dyn BufRead + 'a>> {
+    if zstd {
+        let mut decoder = zstd::stream::read::Decoder::new(reader)?;
+        decoder.window_log_max(ZSTD_WINDOW_LOG_MAX)?;
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(BufReader::new(reader)))
+    }
+}
+
+// .zst dumps are decompressed while reading, anything else is read as jsonl
+pub fn open_dump(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let zstd = path.extension().map_or(false, |x| x == "zst");
+    read_dump(File::open(path)?, zstd)
+}
+
+// Dumps have one submission per line, listings wrap them in a "t3" object
+fn parse_post(line: &[u8]) -> Option<RedditResultDataChildrenData> {
+    serde_json::from_slice::<RedditResultDataChildrenData>(line)
+        .or_else(|_| serde_json::from_slice::<RedditResultDataChildren>(line).map(|x| x.data))
+        .ok()
+}
+
+#[derive(Deserialize)]
+struct PostId {
+    id: String,
+}
+
+fn read_ids(corpus: &Path) -> io::Result<HashSet<String>> {
+    let file = match File::open(corpus) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        file => file?,
+    };
+    let mut ids = HashSet::new();
+    for line in BufReader::new(file).split(b'\n') {
+        if let Ok(post) = serde_json::from_slice::<PostId>(&line?) {
+            ids.insert(post.id);
+        }
+    }
+    Ok(ids)
+}
+
+// Appends the image posts of the dump to the corpus, one json per line,
+// skipping posts it already has. The post url is replaced by the url of its
+// image.
+pub fn import_posts(mut dump: impl BufRead, corpus: &Path) -> io::Result<ImportStats> {
+    let mut ids = read_ids(corpus)?;
+    if let Some(directory) = corpus.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(corpus)?;
+    let mut writer = BufWriter::new(file);
+
+    let mut stats = ImportStats::default();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if dump.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line.iter().all(|x| x.is_ascii_whitespace()) {
+            continue;
+        }
+        stats.lines += 1;
+        let mut post = match parse_post(&line) {
+            Some(post) => post,
+            None => {
+                stats.invalid += 1;
+                continue;
+            }
+        };
+        post.url = match is_image(&post.url) {
+            Some(url) => url,
+            None => {
+                stats.not_images += 1;
+                continue;
+            }
+        };
+        if !ids.insert(post.id.clone()) {
+            stats.duplicates += 1;
+            continue;
+        }
+        serde_json::to_writer(&mut writer, &post)?;
+        writer.write_all(b"\n")?;
+        stats.imported += 1;
+    }
+    writer.flush()?;
+    Ok(stats)
+}
+
+fn get_post_words(post: &RedditResultDataChildrenData) -> Vec<String> {
+    let mut words = get_words(&post.title);
+    words.extend(get_words(&post.subreddit));
+    words
+}
+
+// Posts whose title or subreddit match the query, starting at the cursor,
+// a byte offset in the corpus, and the cursor of the next page
+fn list_page(corpus: &Path, query: &str, cursor: u64) -> io::Result<(Vec<Candidate>, Option<u64>)> {
+    let file = match File::open(corpus) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((vec![], None)),
+        file => file?,
+    };
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(cursor))?;
+
+    let query = parse_query(query);
+    let mut offset = cursor;
+    let mut candidates = vec![];
+    let mut line = Vec::new();
+    loop {
+        if candidates.len() == PAGE_SIZE {
+            return Ok((candidates, Some(offset)));
+        }
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            return Ok((candidates, None));
+        }
+        offset += n as u64;
+        let post = match serde_json::from_slice::<RedditResultDataChildrenData>(&line) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        if query.matches(&get_post_words(&post)) {
+            candidates.extend(get_candidate(post));
+        }
+    }
+}
+
+// Searches the imported posts, without calling reddit
+pub struct CorpusSource {
+    corpus: PathBuf,
+}
+
+impl CorpusSource {
+    pub fn new(corpus: PathBuf) -> CorpusSource {
+        CorpusSource { corpus }
+    }
+}
+
+#[async_trait]
+impl ImageSource for CorpusSource {
+    fn name(&self) -> &str {
+        "corpus"
+    }
+
+    async fn next_page(
+        &self,
+        query: &str,
+        _: &Listing,
+        cursor: Option<String>,
+        _: OnFetchEvent,
+    ) -> Result<Page, image_source::ErrorCode> {
+        let cursor = match cursor {
+            None => 0,
+            Some(cursor) => cursor
+                .parse::<u64>()
+                .or(Err(image_source::ErrorCode::InvalidQuery))?,
+        };
+        let corpus = self.corpus.clone();
+        let query = query.to_owned();
+        let page = tokio::task::spawn_blocking(move || list_page(&corpus, &query, cursor))
+            .await
+            .map_err(|x| image_source::ErrorCode::CannotList(x.to_string()))?;
+        let (candidates, next) =
+            page.map_err(|x| image_source::ErrorCode::CannotList(x.to_string()))?;
+        Ok(Page {
+            candidates,
+            next: next.map(|x| x.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const DUMP: &str = concat!(
+        r#"{"id":"a","url":"https://i.imgur.com/a.jpg","title":"Red Ferrari","subreddit":"cars","#,
+        r#""created_utc":"1262304000","num_comments":3}"#,
+        "\n",
+        r#"{"id":"a","url":"https://i.imgur.com/a.jpg","title":"Red Ferrari","subreddit":"cars"}"#,
+        "\n",
+        r#"{"id":"b","url":"https://example.com/article","title":"Red news","subreddit":"news"}"#,
+        "\n",
+        "not json\n",
+        "\n",
+        r#"{"kind":"t3","data":{"id":"c","url":"https://i.imgur.com/c.gifv","title":"Blue sea","#,
+        r#""subreddit":"EarthPorn"}}"#,
+        "\n",
+    );
+
+    fn corpus_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "search-api-corpus-{}-{}/posts.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn ids(candidates: &[Candidate]) -> Vec<&str> {
+        candidates.iter().map(|x| x.id.as_str()).collect()
+    }
+
+    #[test]
+    fn import_keeps_new_image_posts() {
+        let corpus = corpus_path("import");
+        let stats = import_posts(Cursor::new(DUMP), &corpus).unwrap();
+        assert_eq!((stats.lines, stats.imported, stats.duplicates), (5, 2, 1));
+        assert_eq!((stats.not_images, stats.invalid), (1, 1));
+
+        let stats = import_posts(Cursor::new(DUMP), &corpus).unwrap();
+        assert_eq!((stats.imported, stats.duplicates), (0, 3));
+
+        let (candidates, next) = list_page(&corpus, "", 0).unwrap();
+        assert_eq!(ids(&candidates), vec!["a", "c"]);
+        assert_eq!(candidates[1].url, "https://i.imgur.com/c.gif");
+        assert_eq!(next, None);
+        assert_eq!(
+            ids(&list_page(&corpus, "RED cars", 0).unwrap().0),
+            vec!["a"]
+        );
+        assert_eq!(
+            ids(&list_page(&corpus, "earthporn", 0).unwrap().0),
+            vec!["c"]
+        );
+        assert!(list_page(&corpus, "news", 0).unwrap().0.is_empty());
+        let _ = std::fs::remove_file(&corpus);
+    }
+
+    #[test]
+    fn query_operators_are_followed() {
+        let corpus = corpus_path("operators");
+        import_posts(Cursor::new(DUMP), &corpus).unwrap();
+        let list = |query| ids(&list_page(&corpus, query, 0).unwrap().0).join(",");
+        assert_eq!(list("ferrari OR sea"), "a,c");
+        assert_eq!(list("NOT ferrari"), "c");
+        assert_eq!(list("red -cars"), "");
+        assert_eq!(list("\"blue sea\""), "c");
+        let _ = std::fs::remove_file(&corpus);
+    }
+
+    #[test]
+    fn pages_continue_at_the_cursor() {
+        let corpus = corpus_path("pages");
+        let dump: String = (0..PAGE_SIZE + 5)
+            .map(|i| {
+                format!(
+                    "{{\"id\":\"{}\",\"url\":\"https://i.imgur.com/{}.png\"}}\n",
+                    i, i
+                )
+            })
+            .collect();
+        import_posts(Cursor::new(dump), &corpus).unwrap();
+        let (first, next) = list_page(&corpus, "", 0).unwrap();
+        assert_eq!(first.len(), PAGE_SIZE);
+        let (second, next) = list_page(&corpus, "", next.unwrap()).unwrap();
+        assert_eq!(ids(&second), vec!["100", "101", "102", "103", "104"]);
+        assert_eq!(next, None);
+        let _ = std::fs::remove_file(&corpus);
+    }
+
+    #[test]
+    fn read_dump_decompresses_zstd() {
+        let compressed = zstd::encode_all(DUMP.as_bytes(), 3).unwrap();
+        let mut decompressed = String::new();
+        read_dump(Cursor::new(compressed), true)
+            .unwrap()
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, DUMP);
+    }
+}
@@ -0,0 +1,242 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+use uriparse::{Host, URI};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ErrorCode {
+    #[error("invalid url")]
+    InvalidUrl,
+    #[error("scheme {0} is not allowed")]
+    UnsupportedScheme(String),
+    #[error("host {0} is denied")]
+    DeniedHost(String),
+    #[error("cannot resolve host {0}")]
+    CannotResolve(String),
+    #[error("address {0} is not allowed")]
+    ForbiddenAddress(IpAddr),
+}
+
+// Urls come from reddit posts and their redirects, so they must not reach
+// the machine itself, the internal network or cloud metadata services.
+#[derive(Clone, Debug, Default)]
+pub struct FetchPolicy {
+    // trusted hosts, fetched even when they resolve to a private address
+    pub allow_hosts: Vec<String>,
+    // hosts never fetched, wherever they resolve
+    pub deny_hosts: Vec<String>,
+}
+
+fn is_forbidden_ipv4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network", shared address space, IETF protocol assignments,
+        // benchmarking and reserved ranges
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240
+}
+
+// The ipv4 address a NAT64 (64:ff9b::/96) or 6to4 (2002::/16) address
+// reaches
+fn get_embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let ipv4 = |high: u16, low: u16| {
+        Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8)
+    };
+    if s[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        Some(ipv4(s[6], s[7]))
+    } else if s[0] == 0x2002 {
+        Some(ipv4(s[1], s[2]))
+    } else {
+        None
+    }
+}
+
+fn is_forbidden_ipv6(ip: &Ipv6Addr) -> bool {
+    // ipv4 mapped and compatible addresses
+    if let Some(ip) = ip.to_ipv4() {
+        return is_forbidden_ipv4(&ip);
+    }
+    if let Some(ip) = get_embedded_ipv4(ip) {
+        return is_forbidden_ipv4(&ip);
+    }
+    let [first, second, ..] = ip.segments();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // local use NAT64 prefix 64:ff9b:1::/48
+        || (first == 0x64 && second == 0xff9b)
+        // unique local, including fd00:ec2::254, and link local
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+pub fn is_forbidden_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_forbidden_ipv4(ip),
+        IpAddr::V6(ip) => is_forbidden_ipv6(ip),
+    }
+}
+
+fn matches_host(host: &str, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.trim_start_matches('.').to_lowercase();
+        host == pattern || host.ends_with(&format!(".{}", pattern))
+    })
+}
+
+// Splits a url into its lowercase host and port, rejecting anything that is
+// not http or https
+pub fn get_host_and_port(url: &str) -> Result<(String, u16), ErrorCode> {
+    let uri = URI::try_from(url).or(Err(ErrorCode::InvalidUrl))?;
+    let scheme = uri.scheme().as_str().to_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return Err(ErrorCode::UnsupportedScheme(scheme)),
+    };
+    let host = match uri.host().ok_or(ErrorCode::InvalidUrl)? {
+        Host::RegisteredName(name) => name.as_str().to_lowercase(),
+        Host::IPv4Address(ip) => ip.to_string(),
+        Host::IPv6Address(ip) => ip.to_string(),
+    };
+    if host.is_empty() {
+        return Err(ErrorCode::InvalidUrl);
+    }
+    Ok((host, uri.port().unwrap_or(default_port)))
+}
+
+impl FetchPolicy {
+    // Checked before every request, so a redirect cannot escape the policy.
+    // Returns the checked address the request must connect to, so the host
+    // cannot be rebound to another one in between. None for allowed hosts,
+    // which the client resolves itself.
+    pub async fn check(&self, url: &str) -> Result<Option<SocketAddr>, ErrorCode> {
+        let (host, port) = get_host_and_port(url)?;
+        if matches_host(&host, &self.deny_hosts) {
+            return Err(ErrorCode::DeniedHost(host));
+        }
+        if matches_host(&host, &self.allow_hosts) {
+            return Ok(None);
+        }
+
+        let addresses = tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .map_err(|_| ErrorCode::CannotResolve(host.clone()))?;
+        let mut resolved = None;
+        for address in addresses {
+            if is_forbidden_ip(&address.ip()) {
+                return Err(ErrorCode::ForbiddenAddress(address.ip()));
+            }
+            resolved = resolved.or(Some(address));
+        }
+        match resolved {
+            Some(address) => Ok(Some(address)),
+            None => Err(ErrorCode::CannotResolve(host)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[quickcheck]
+    fn private_ipv4_addresses_are_forbidden(b: u8, c: u8, d: u8) -> bool {
+        is_forbidden_ip(&IpAddr::V4(Ipv4Addr::new(10, b, c, d)))
+            && is_forbidden_ip(&IpAddr::V4(Ipv4Addr::new(127, b, c, d)))
+            && is_forbidden_ip(&IpAddr::V4(Ipv4Addr::new(169, 254, c, d)))
+            && is_forbidden_ip(&IpAddr::V4(Ipv4Addr::new(192, 168, c, d)))
+            && is_forbidden_ip(&IpAddr::V4(Ipv4Addr::new(172, 16 + b % 16, c, d)))
+            && is_forbidden_ip(&IpAddr::V4(Ipv4Addr::new(100, 64 + b % 64, c, d)))
+    }
+
+    #[quickcheck]
+    fn mapped_ipv6_addresses_follow_ipv4(a: u8, b: u8, c: u8, d: u8) -> bool {
+        let ip = Ipv4Addr::new(a, b, c, d);
+        is_forbidden_ip(&IpAddr::V6(ip.to_ipv6_mapped())) == is_forbidden_ip(&IpAddr::V4(ip))
+    }
+
+    #[quickcheck]
+    fn nat64_and_6to4_addresses_follow_ipv4(a: u8, b: u8, c: u8, d: u8) -> bool {
+        let ip = Ipv4Addr::new(a, b, c, d);
+        let [high, low] = [u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d])];
+        let nat64 = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, high, low);
+        let six_to_four = Ipv6Addr::new(0x2002, high, low, 0, 0, 0, 0, 1);
+        is_forbidden_ip(&IpAddr::V6(nat64)) == is_forbidden_ip(&IpAddr::V4(ip))
+            && is_forbidden_ip(&IpAddr::V6(six_to_four)) == is_forbidden_ip(&IpAddr::V4(ip))
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        let addresses = [
+            "8.8.8.8",
+            "151.101.1.140",
+            "2a04:4e42::396",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ];
+        for address in addresses.iter() {
+            assert!(!is_forbidden_ip(&address.parse().unwrap()), "{}", address);
+        }
+        let addresses = [
+            "::1",
+            "fe80::1",
+            "fd00:ec2::254",
+            "169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::808:808",
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+        ];
+        for address in addresses.iter() {
+            assert!(is_forbidden_ip(&address.parse().unwrap()), "{}", address);
+        }
+    }
+
+    #[test]
+    fn only_http_and_https_are_allowed() {
+        assert_eq!(
+            get_host_and_port("HTTPS://I.Imgur.com/a.png"),
+            Ok(("i.imgur.com".to_owned(), 443))
+        );
+        assert_eq!(
+            get_host_and_port("http://127.0.0.1:8000/a.png"),
+            Ok(("127.0.0.1".to_owned(), 8000))
+        );
+        assert_eq!(
+            get_host_and_port("file:///etc/passwd"),
+            Err(ErrorCode::UnsupportedScheme("file".to_owned()))
+        );
+        assert_eq!(
+            get_host_and_port("gopher://localhost:6379/_INFO"),
+            Err(ErrorCode::UnsupportedScheme("gopher".to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_decide_before_resolving() {
+        let policy = FetchPolicy {
+            allow_hosts: vec!["localhost".to_owned()],
+            deny_hosts: vec!["example.com".to_owned()],
+        };
+        assert_eq!(policy.check("http://localhost/a.png").await, Ok(None));
+        assert_eq!(
+            policy.check("http://cdn.example.com/a.png").await,
+            Err(ErrorCode::DeniedHost("cdn.example.com".to_owned()))
+        );
+        assert_eq!(
+            FetchPolicy::default().check("http://127.0.0.1/a.png").await,
+            Err(ErrorCode::ForbiddenAddress("127.0.0.1".parse().unwrap()))
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use serde_json::Value;
+
+use crate::image_source::Candidate;
+
+// Which candidates a search accepts, decided from their metadata before their
+// image is downloaded. Candidates without a field fail the filters on it.
+// Only posts can be nsfw, so other candidates, like local images, always
+// pass the nsfw filter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CandidateFilter {
+    pub nsfw: bool,
+    pub min_score: Option<i64>,
+    pub min_comments: Option<u64>,
+    // unix seconds
+    pub since: Option<f64>,
+    pub until: Option<f64>,
+    pub subreddit: Option<String>,
+    // also matches its subdomains
+    pub domain: Option<String>,
+}
+
+impl Default for CandidateFilter {
+    fn default() -> CandidateFilter {
+        CandidateFilter {
+            nsfw: true,
+            min_score: None,
+            min_comments: None,
+            since: None,
+            until: None,
+            subreddit: None,
+            domain: None,
+        }
+    }
+}
+
+// Reddit posts, listed or imported, have a subreddit
+fn is_post(candidate: &Candidate) -> bool {
+    candidate.metadata.contains_key("subreddit")
+}
+
+// Posts listed, imported or indexed before their nsfw flag was kept, which
+// may or may not be nsfw
+pub fn has_unknown_nsfw(candidate: &Candidate) -> bool {
+    is_post(candidate) && !candidate.metadata.contains_key("over_18")
+}
+
+fn is_domain_of(host: &str, domain: &str) -> bool {
+    let host = host.to_lowercase();
+    let domain = domain.to_lowercase();
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+impl CandidateFilter {
+    pub fn matches(&self, candidate: &Candidate) -> bool {
+        let get = |field: &str| candidate.metadata.get(field);
+        if !self.nsfw {
+            let over_18 = get("over_18").and_then(Value::as_bool);
+            if over_18.unwrap_or_else(|| is_post(candidate)) {
+                return false;
+            }
+        }
+        if let Some(min_score) = self.min_score {
+            if !get("score")
+                .and_then(Value::as_i64)
+                .map_or(false, |x| x >= min_score)
+            {
+                return false;
+            }
+        }
+        if let Some(min_comments) = self.min_comments {
+            if !get("num_comments")
+                .and_then(Value::as_u64)
+                .map_or(false, |x| x >= min_comments)
+            {
+                return false;
+            }
+        }
+        let created = get("created_utc").and_then(Value::as_f64);
+        if let Some(since) = self.since {
+            if !created.map_or(false, |x| x >= since) {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if !created.map_or(false, |x| x < until) {
+                return false;
+            }
+        }
+        if let Some(subreddit) = &self.subreddit {
+            if !get("subreddit")
+                .and_then(Value::as_str)
+                .map_or(false, |x| x.eq_ignore_ascii_case(subreddit))
+            {
+                return false;
+            }
+        }
+        if let Some(domain) = &self.domain {
+            if !get("domain")
+                .and_then(Value::as_str)
+                .map_or(false, |x| is_domain_of(x, domain))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn post(metadata: Value) -> Candidate {
+        Candidate {
+            id: "a".to_owned(),
+            url: "b".to_owned(),
+            metadata: metadata.as_object().cloned().unwrap(),
+        }
+    }
+
+    #[test]
+    fn filters_read_post_fields() {
+        let candidate = post(serde_json::json!({
+            "over_18": true,
+            "score": 10,
+            "num_comments": 3,
+            "created_utc": 1262304000.0,
+            "subreddit": "EarthPorn",
+            "domain": "i.imgur.com"
+        }));
+        let matches = |filter: CandidateFilter| filter.matches(&candidate);
+        let default = CandidateFilter::default;
+        assert!(matches(default()));
+        assert!(!matches(CandidateFilter {
+            nsfw: false,
+            ..default()
+        }));
+        assert!(matches(CandidateFilter {
+            min_score: Some(10),
+            min_comments: Some(3),
+            ..default()
+        }));
+        assert!(!matches(CandidateFilter {
+            min_score: Some(11),
+            ..default()
+        }));
+        assert!(matches(CandidateFilter {
+            since: Some(1262304000.0),
+            until: Some(1262304001.0),
+            ..default()
+        }));
+        assert!(!matches(CandidateFilter {
+            until: Some(1262304000.0),
+            ..default()
+        }));
+        assert!(matches(CandidateFilter {
+            subreddit: Some("earthporn".to_owned()),
+            domain: Some("imgur.com".to_owned()),
+            ..default()
+        }));
+        assert!(!matches(CandidateFilter {
+            domain: Some("gur.com".to_owned()),
+            ..default()
+        }));
+    }
+
+    #[test]
+    fn posts_without_the_nsfw_flag_are_excluded() {
+        // as the corpus and the index have posts from before the flag was kept
+        let candidate = post(serde_json::json!({
+            "title": "Red Ferrari",
+            "subreddit": "cars",
+            "num_comments": 3,
+            "created_utc": 1262304000.0
+        }));
+        assert!(has_unknown_nsfw(&candidate));
+        assert!(CandidateFilter::default().matches(&candidate));
+        let only = |filter: CandidateFilter| filter.matches(&candidate);
+        assert!(!only(CandidateFilter {
+            nsfw: false,
+            ..CandidateFilter::default()
+        }));
+        assert!(!only(CandidateFilter {
+            min_score: Some(0),
+            ..CandidateFilter::default()
+        }));
+        assert!(!only(CandidateFilter {
+            domain: Some("imgur.com".to_owned()),
+            ..CandidateFilter::default()
+        }));
+        assert!(only(CandidateFilter {
+            min_comments: Some(3),
+            subreddit: Some("cars".to_owned()),
+            ..CandidateFilter::default()
+        }));
+    }
+
+    #[test]
+    fn missing_fields_only_pass_the_nsfw_filter() {
+        let candidate = post(serde_json::json!({ "tags": ["red"] }));
+        assert!(!has_unknown_nsfw(&candidate));
+        assert!(CandidateFilter {
+            nsfw: false,
+            ..CandidateFilter::default()
+        }
+        .matches(&candidate));
+        assert!(!CandidateFilter {
+            min_score: Some(0),
+            ..CandidateFilter::default()
+        }
+        .matches(&candidate));
+    }
+}
@@ -0,0 +1,196 @@
+use image::ImageFormat;
+use serde::Serialize;
+
+pub struct SupportedFormat {
+    pub format: ImageFormat,
+    pub name: &'static str,
+    pub extensions: &'static [&'static str],
+    pub mime_type: &'static str,
+    pub animated: bool,
+}
+
+const FORMATS: &[SupportedFormat] = &[
+    SupportedFormat {
+        format: ImageFormat::Jpeg,
+        name: "jpeg",
+        extensions: &["jpg", "jpeg"],
+        mime_type: "image/jpeg",
+        animated: false,
+    },
+    SupportedFormat {
+        format: ImageFormat::Png,
+        name: "png",
+        extensions: &["png"],
+        mime_type: "image/png",
+        animated: true,
+    },
+    SupportedFormat {
+        format: ImageFormat::Gif,
+        name: "gif",
+        extensions: &["gif"],
+        mime_type: "image/gif",
+        animated: true,
+    },
+    // animated webp is rejected, the decoder reads still images only
+    SupportedFormat {
+        format: ImageFormat::WebP,
+        name: "webp",
+        extensions: &["webp"],
+        mime_type: "image/webp",
+        animated: false,
+    },
+    SupportedFormat {
+        format: ImageFormat::Bmp,
+        name: "bmp",
+        extensions: &["bmp"],
+        mime_type: "image/bmp",
+        animated: false,
+    },
+    SupportedFormat {
+        format: ImageFormat::Tiff,
+        name: "tiff",
+        extensions: &["tif", "tiff"],
+        mime_type: "image/tiff",
+        animated: false,
+    },
+];
+
+// Decoding AVIF needs dav1d, so it is only available with the "avif" feature
+const AVIF: SupportedFormat = SupportedFormat {
+    format: ImageFormat::Avif,
+    name: "avif",
+    extensions: &["avif"],
+    mime_type: "image/avif",
+    animated: false,
+};
+
+pub fn supported_formats() -> Vec<&'static SupportedFormat> {
+    let mut formats: Vec<&'static SupportedFormat> = FORMATS.iter().collect();
+    if cfg!(feature = "avif") {
+        formats.push(&AVIF);
+    }
+    formats
+}
+
+pub fn has_image_extension(url: &str) -> bool {
+    let url = url.to_lowercase();
+    supported_formats().iter().any(|x| {
+        x.extensions
+            .iter()
+            .any(|extension| url.ends_with(&format!(".{}", extension)))
+    })
+}
+
+// Detects the format from the content, whatever the url says
+pub fn sniff_format(data: &[u8]) -> Option<ImageFormat> {
+    let format = image::guess_format(data).ok()?;
+    if supported_formats().iter().any(|x| x.format == format) {
+        Some(format)
+    } else {
+        None
+    }
+}
+
+// An extended webp header with the animation flag set
+pub fn is_animated_webp(data: &[u8]) -> bool {
+    data.len() > 20
+        && &data[0..4] == b"RIFF"
+        && &data[8..12] == b"WEBP"
+        && &data[12..16] == b"VP8X"
+        && data[20] & 0x02 != 0
+}
+
+pub fn get_mime_type(data: &[u8]) -> Option<&'static str> {
+    let format = sniff_format(data)?;
+    supported_formats()
+        .iter()
+        .find(|x| x.format == format)
+        .map(|x| x.mime_type)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    formats: Vec<&'static str>,
+    animated_formats: Vec<&'static str>,
+    extensions: Vec<&'static str>,
+}
+
+pub fn get_capabilities() -> Capabilities {
+    let formats = supported_formats();
+    Capabilities {
+        formats: formats.iter().map(|x| x.name).collect(),
+        animated_formats: formats
+            .iter()
+            .filter(|x| x.animated)
+            .map(|x| x.name)
+            .collect(),
+        extensions: formats
+            .iter()
+            .flat_map(|x| x.extensions.iter().copied())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, image::Rgb([200, 10, 10])));
+        let mut data = Vec::new();
+        img.write_to(&mut data, format).unwrap();
+        data
+    }
+
+    #[test]
+    fn sniff_format_ignores_extension() {
+        assert_eq!(
+            sniff_format(&encode(ImageOutputFormat::Png)),
+            Some(ImageFormat::Png)
+        );
+        assert_eq!(
+            sniff_format(&encode(ImageOutputFormat::Jpeg(90))),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            sniff_format(&encode(ImageOutputFormat::Gif)),
+            Some(ImageFormat::Gif)
+        );
+        assert_eq!(
+            sniff_format(&encode(ImageOutputFormat::Bmp)),
+            Some(ImageFormat::Bmp)
+        );
+        assert_eq!(sniff_format(b"<html></html>"), None);
+    }
+
+    #[test]
+    fn get_mime_type_follows_content() {
+        assert_eq!(
+            get_mime_type(&encode(ImageOutputFormat::Jpeg(90))),
+            Some("image/jpeg")
+        );
+        assert_eq!(get_mime_type(b"<html></html>"), None);
+    }
+
+    #[test]
+    fn is_animated_webp_reads_the_animation_flag() {
+        let header = |flags: u8| {
+            let mut data = b"RIFF\x1e\0\0\0WEBPVP8X\x0a\0\0\0".to_vec();
+            data.extend_from_slice(&[flags, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            data
+        };
+        assert!(is_animated_webp(&header(0x02)));
+        assert!(is_animated_webp(&header(0x12)));
+        assert!(!is_animated_webp(&header(0x10)));
+        assert!(!is_animated_webp(&encode(ImageOutputFormat::Png)));
+    }
+
+    #[quickcheck]
+    fn has_image_extension_ignores_case(path: String, i: usize) -> bool {
+        let extensions = get_capabilities().extensions;
+        let extension = extensions[i % extensions.len()];
+        has_image_extension(&format!("{}.{}", path, extension))
+            && has_image_extension(&format!("{}.{}", path, extension.to_uppercase()))
+    }
+}
@@ -0,0 +1,725 @@
+use futures::AsyncReadExt;
+use isahc::config::ResolveMap;
+use isahc::prelude::*;
+use isahc::{Body, HttpClient};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::actors::rate_limit::{parse_rate_limit, RateLimitMessage};
+use crate::fetch_policy::{self, FetchPolicy};
+use async_channel::Sender;
+
+const USER_AGENT: &str = concat!(
+    "linux:search-api:",
+    env!("CARGO_PKG_VERSION"),
+    " (searches reddit images by color)"
+);
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ErrorCode {
+    #[error("cannot build http client")]
+    CannotBuildClient,
+    #[error("cannot send request")]
+    CannotSend,
+    #[error("cannot read response body")]
+    CannotReadBody,
+    #[error("unexpected status {0}")]
+    UnexpectedStatus(u16),
+    #[error("redirect without location")]
+    MissingLocation,
+    #[error("invalid redirect location")]
+    InvalidLocation,
+    #[error("redirect loop")]
+    RedirectLoop,
+    #[error("more than {0} redirects")]
+    TooManyRedirects(usize),
+    #[error("response larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("{0}")]
+    Forbidden(#[from] fetch_policy::ErrorCode),
+}
+
+impl ErrorCode {
+    // Connection errors, server errors and throttling may go away by
+    // themselves, anything else fails the same way again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ErrorCode::CannotSend | ErrorCode::CannotReadBody => true,
+            ErrorCode::UnexpectedStatus(status) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FetchOptions {
+    pub max_redirects: usize,
+    pub connect_timeout: Duration,
+    // whole request, body included
+    pub timeout: Duration,
+    pub max_response_bytes: u64,
+    pub max_connections_per_host: usize,
+    // attempts of each fetch, the first one included
+    pub max_attempts: usize,
+    pub retry_base_delay: Duration,
+    pub retry_max_delay: Duration,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            max_redirects: 10,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            max_response_bytes: 20 * 1024 * 1024,
+            max_connections_per_host: 8,
+            max_attempts: 3,
+            retry_base_delay: Duration::from_millis(250),
+            retry_max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Retry {
+    pub url: String,
+    // attempt that failed, starting at 1
+    pub attempt: usize,
+    pub delay_ms: u64,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchEvent {
+    Retry(Retry),
+    RateLimited { url: String, wait_ms: u64 },
+}
+
+pub type OnFetchEvent = Box<dyn FnMut(&FetchEvent) + Send>;
+
+// Exponential backoff capped at retry_max_delay, with the upper half of the
+// delay randomized so concurrent fetches do not retry in lockstep. jitter is
+// in 0..1.
+pub fn backoff_delay(options: &FetchOptions, attempt: usize, jitter: f32) -> Duration {
+    let exponential = options
+        .retry_base_delay
+        .checked_mul(1 << (attempt.max(1) - 1).min(16) as u32)
+        .unwrap_or(options.retry_max_delay);
+    let capped = exponential.min(options.retry_max_delay);
+    capped / 2 + capped.mul_f32(jitter.max(0.0).min(1.0) / 2.0)
+}
+
+pub struct Fetched {
+    // url of the last hop, after all redirects
+    pub url: String,
+    pub body: Vec<u8>,
+}
+
+pub fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+fn has_scheme(url: &str) -> bool {
+    match url.find(':') {
+        None => false,
+        Some(end) => {
+            let scheme = &url[..end];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+    }
+}
+
+fn remove_dot_segments(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    let mut output: Vec<&str> = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        match *segment {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            segment => {
+                output.push(segment);
+                continue;
+            }
+        }
+        if last {
+            output.push("");
+        }
+    }
+    format!("/{}", output.join("/"))
+}
+
+fn find_path_end(s: &str) -> usize {
+    s.find(|c| c == '?' || c == '#').unwrap_or_else(|| s.len())
+}
+
+// Resolves a Location header against the url that returned it, following
+// RFC 3986 section 5.2
+pub fn resolve_url(base: &str, reference: &str) -> Option<String> {
+    let reference = reference.trim();
+    if has_scheme(reference) {
+        return Some(reference.to_owned());
+    }
+
+    let scheme_end = base.find("://")?;
+    let scheme = &base[..scheme_end];
+    if reference.starts_with("//") {
+        return Some(format!("{}:{}", scheme, reference));
+    }
+
+    if reference.starts_with('#') {
+        return Some(format!(
+            "{}{}",
+            &base[..base.find('#').unwrap_or_else(|| base.len())],
+            reference
+        ));
+    }
+
+    let rest = &base[scheme_end + 3..];
+    let authority_end = rest
+        .find(|c| c == '/' || c == '?' || c == '#')
+        .unwrap_or_else(|| rest.len());
+    let authority = &rest[..authority_end];
+    let base_path = &rest[authority_end..][..find_path_end(&rest[authority_end..])];
+    let base_path = if base_path.is_empty() { "/" } else { base_path };
+
+    let path_end = find_path_end(reference);
+    let (path, query) = reference.split_at(path_end);
+    let path = if path.is_empty() {
+        base_path.to_owned()
+    } else if path.starts_with('/') {
+        remove_dot_segments(path)
+    } else {
+        let directory = &base_path[..base_path.rfind('/').unwrap_or(0) + 1];
+        remove_dot_segments(&format!("{}{}", directory, path))
+    };
+    Some(format!("{}://{}{}{}", scheme, authority, path, query))
+}
+
+// Connects to the address checked by the fetch policy instead of resolving
+// the host again, so DNS cannot answer with another address in between.
+// Addresses in the url need no resolving.
+fn pin_address<T>(
+    request: &mut Request<T>,
+    url: &str,
+    address: Option<SocketAddr>,
+) -> Result<(), ErrorCode> {
+    let (host, port) = fetch_policy::get_host_and_port(url)?;
+    if let (Some(address), Err(_)) = (address, host.parse::<IpAddr>()) {
+        let resolve = ResolveMap::new().add(host, port, address.ip());
+        request.extensions_mut().insert(resolve);
+    }
+    Ok(())
+}
+
+fn content_length(response: &Response<Body>) -> Option<u64> {
+    response
+        .headers()
+        .get("Content-Length")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+// One client for every outgoing request, so connections are reused and all of
+// them share the same timeouts, limits and User-Agent
+#[derive(Clone)]
+pub struct Client {
+    client: Arc<HttpClient>,
+    options: FetchOptions,
+    policy: Arc<FetchPolicy>,
+    rate_limit: Option<Sender<RateLimitMessage>>,
+    // Authorization header value
+    authorization: Option<String>,
+}
+
+impl Client {
+    pub fn new(options: FetchOptions, policy: FetchPolicy) -> Result<Client, ErrorCode> {
+        let client = HttpClient::builder()
+            .connect_timeout(options.connect_timeout)
+            .timeout(options.timeout)
+            .automatic_decompression(true)
+            .max_connections_per_host(options.max_connections_per_host)
+            .build()
+            .or(Err(ErrorCode::CannotBuildClient))?;
+        Ok(Client {
+            client: Arc::new(client),
+            options,
+            policy: Arc::new(policy),
+            rate_limit: None,
+            authorization: None,
+        })
+    }
+
+    // Same connections, but every request is scheduled by the rate limit
+    // actor, which learns the limits from the response headers
+    pub fn with_rate_limit(&self, rate_limit: Sender<RateLimitMessage>) -> Client {
+        Client {
+            rate_limit: Some(rate_limit),
+            ..self.clone()
+        }
+    }
+
+    // Same connections, sending the Authorization header to the host of each
+    // fetched url but never to the hosts it redirects to
+    pub fn with_authorization(&self, authorization: String) -> Client {
+        Client {
+            authorization: Some(authorization),
+            ..self.clone()
+        }
+    }
+
+    async fn wait_rate_limit(&self, url: &str, on_event: &mut impl FnMut(&FetchEvent)) {
+        let rate_limit = match &self.rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => return,
+        };
+        let (w, s) = oneshot::channel();
+        if rate_limit.send(RateLimitMessage::Acquire(w)).await.is_err() {
+            return;
+        }
+        if let Ok(wait) = s.await {
+            if wait > Duration::from_millis(0) {
+                on_event(&FetchEvent::RateLimited {
+                    url: url.to_owned(),
+                    wait_ms: wait.as_millis() as u64,
+                });
+                tokio::time::delay_for(wait).await;
+            }
+        }
+    }
+
+    // address is where the policy checked the host resolves
+    async fn get(
+        &self,
+        url: &str,
+        authorized: bool,
+        address: Option<SocketAddr>,
+        on_event: &mut impl FnMut(&FetchEvent),
+    ) -> Result<Response<Body>, ErrorCode> {
+        self.wait_rate_limit(url, on_event).await;
+        let mut request = Request::get(url).header("User-Agent", USER_AGENT);
+        if let (true, Some(authorization)) = (authorized, &self.authorization) {
+            request = request.header("Authorization", authorization.as_str());
+        }
+        let mut request = request.body(()).or(Err(ErrorCode::CannotSend))?;
+        pin_address(&mut request, url, address)?;
+        let response = self
+            .client
+            .send_async(request)
+            .await
+            .or(Err(ErrorCode::CannotSend))?;
+        if let Some(rate_limit) = &self.rate_limit {
+            if let Some(limit) = parse_rate_limit(response.status().as_u16(), response.headers()) {
+                let _ = rate_limit.send(RateLimitMessage::Update(limit)).await;
+            }
+        }
+        Ok(response)
+    }
+
+    // Stops reading as soon as the body is over the limit, whatever
+    // Content-Length says
+    async fn read_body(&self, response: &mut Response<Body>) -> Result<Vec<u8>, ErrorCode> {
+        let max = self.options.max_response_bytes;
+        if content_length(response).map_or(false, |x| x > max) {
+            return Err(ErrorCode::TooLarge(max));
+        }
+        let mut body = Vec::new();
+        response
+            .body_mut()
+            .take(max + 1)
+            .read_to_end(&mut body)
+            .await
+            .or(Err(ErrorCode::CannotReadBody))?;
+        if body.len() as u64 > max {
+            return Err(ErrorCode::TooLarge(max));
+        }
+        Ok(body)
+    }
+
+    // Single POST of an urlencoded form, without retries or redirects
+    pub async fn post_form(
+        &self,
+        url: &str,
+        authorization: &str,
+        form: &str,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let address = self.policy.check(url).await?;
+        let mut request = Request::post(url)
+            .header("User-Agent", USER_AGENT)
+            .header("Authorization", authorization)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(form.to_owned())
+            .or(Err(ErrorCode::CannotSend))?;
+        pin_address(&mut request, url, address)?;
+        let mut response = self
+            .client
+            .send_async(request)
+            .await
+            .or(Err(ErrorCode::CannotSend))?;
+        match response.status().as_u16() {
+            200 => self.read_body(&mut response).await,
+            status => Err(ErrorCode::UnexpectedStatus(status)),
+        }
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Fetched, ErrorCode> {
+        self.fetch_reporting(url, |_| {}).await
+    }
+
+    // fetch, retrying transient failures up to max_attempts times. on_event
+    // is called before waiting for a retry or for the rate limit.
+    pub async fn fetch_reporting(
+        &self,
+        url: &str,
+        mut on_event: impl FnMut(&FetchEvent),
+    ) -> Result<Fetched, ErrorCode> {
+        let mut attempt = 1;
+        loop {
+            match self.fetch_once(url, &mut on_event).await {
+                Err(err) if err.is_transient() && attempt < self.options.max_attempts => {
+                    let delay = backoff_delay(&self.options, attempt, rand::random());
+                    on_event(&FetchEvent::Retry(Retry {
+                        url: url.to_owned(),
+                        attempt,
+                        delay_ms: delay.as_millis() as u64,
+                        reason: err.to_string(),
+                    }));
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // GET that follows every kind of redirect, relative ones included, up to
+    // max_redirects hops and never visiting the same url twice. Every hop
+    // must pass the fetch policy.
+    async fn fetch_once(
+        &self,
+        url: &str,
+        on_event: &mut impl FnMut(&FetchEvent),
+    ) -> Result<Fetched, ErrorCode> {
+        let host = fetch_policy::get_host_and_port(url)?;
+        let mut url = url.to_owned();
+        let mut visited = HashSet::new();
+        loop {
+            if !visited.insert(url.clone()) {
+                return Err(ErrorCode::RedirectLoop);
+            }
+
+            let address = self.policy.check(&url).await?;
+            let authorized = fetch_policy::get_host_and_port(&url)? == host;
+            let mut response = self.get(&url, authorized, address, on_event).await?;
+            let status = response.status().as_u16();
+            if status == 200 {
+                let body = self.read_body(&mut response).await?;
+                return Ok(Fetched { url, body });
+            } else if is_redirect(status) {
+                if visited.len() > self.options.max_redirects {
+                    return Err(ErrorCode::TooManyRedirects(self.options.max_redirects));
+                }
+                let location = response
+                    .headers()
+                    .get("Location")
+                    .ok_or(ErrorCode::MissingLocation)?
+                    .to_str()
+                    .or(Err(ErrorCode::InvalidLocation))?;
+                url = resolve_url(&url, location).ok_or(ErrorCode::InvalidLocation)?;
+            } else {
+                return Err(ErrorCode::UnexpectedStatus(status));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::actors::rate_limit::spawn_rate_limit;
+    use crate::test_server::{ok, redirect, serve, status};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Only the test server itself is trusted
+    fn local_client_with(options: FetchOptions) -> Client {
+        let policy = FetchPolicy {
+            allow_hosts: vec!["127.0.0.1".to_owned()],
+            deny_hosts: vec![],
+        };
+        Client::new(options, policy).unwrap()
+    }
+
+    fn local_client(max_response_bytes: u64) -> Client {
+        local_client_with(FetchOptions {
+            max_response_bytes,
+            ..FetchOptions::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn fetch_follows_relative_redirects() {
+        let url = serve(|request| match request.path.as_str() {
+            "/a/b" => redirect("../c"),
+            "/c" => redirect("/d"),
+            _ => ok("image"),
+        })
+        .await;
+        let fetched = local_client(1024)
+            .fetch(&format!("{}/a/b", url))
+            .await
+            .unwrap();
+        assert_eq!(fetched.url, format!("{}/d", url));
+        assert_eq!(fetched.body, b"image");
+    }
+
+    #[tokio::test]
+    async fn fetch_detects_redirect_loops() {
+        let url = serve(|request| match request.path.as_str() {
+            "/a" => redirect("/b"),
+            _ => redirect("/a"),
+        })
+        .await;
+        let fetched = local_client(1024).fetch(&format!("{}/a", url)).await;
+        assert_eq!(fetched.err(), Some(ErrorCode::RedirectLoop));
+    }
+
+    #[tokio::test]
+    async fn fetch_limits_the_number_of_redirects() {
+        let url = serve(|request| match request.path.as_str() {
+            "/0" => redirect("/1"),
+            "/1" => redirect("/2"),
+            "/2" => redirect("/3"),
+            _ => ok("image"),
+        })
+        .await;
+        let client = local_client_with(FetchOptions {
+            max_redirects: 2,
+            ..FetchOptions::default()
+        });
+        let fetched = client.fetch(&format!("{}/1", url)).await.unwrap();
+        assert_eq!(fetched.url, format!("{}/3", url));
+        let fetched = client.fetch(&format!("{}/0", url)).await;
+        assert_eq!(fetched.err(), Some(ErrorCode::TooManyRedirects(2)));
+    }
+
+    #[tokio::test]
+    async fn requests_connect_to_the_checked_address() {
+        let url = serve(|_| ok("image")).await;
+        let address: SocketAddr = url.trim_start_matches("http://").parse().unwrap();
+        let pinned = format!("http://pinned.invalid:{}/a", address.port());
+        let response = local_client(1024)
+            .get(&pinned, false, Some(address), &mut |_| {})
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    #[tokio::test]
+    async fn fetch_rejects_redirects_to_forbidden_targets() {
+        let url = serve(|request| match request.path.as_str() {
+            "/metadata" => redirect("http://169.254.169.254/latest/meta-data/"),
+            "/localhost" => redirect("http://localhost:6379/"),
+            "/private" => redirect("http://10.0.0.1/a.png"),
+            "/ipv6" => redirect("http://[::1]/a.png"),
+            "/file" => redirect("file:///etc/passwd"),
+            _ => ok("image"),
+        })
+        .await;
+        let client = local_client(1024);
+        for path in ["/metadata", "/localhost", "/private", "/ipv6", "/file"].iter() {
+            match client.fetch(&format!("{}{}", url, path)).await {
+                Err(ErrorCode::Forbidden(_)) => {}
+                other => panic!("{} was not forbidden: {:?}", path, other.err()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn authorization_is_not_sent_to_other_hosts() {
+        let url = serve(|request| match request.path.as_str() {
+            "/same" => redirect("/whoami"),
+            // same server, but a different host
+            "/other" => {
+                let port = request.header("host").unwrap().split(':').nth(1).unwrap();
+                redirect(&format!("http://localhost:{}/whoami", port))
+            }
+            _ => ok(request.header("authorization").unwrap_or("anonymous")),
+        })
+        .await;
+        let policy = FetchPolicy {
+            allow_hosts: vec!["127.0.0.1".to_owned(), "localhost".to_owned()],
+            deny_hosts: vec![],
+        };
+        let client = Client::new(FetchOptions::default(), policy)
+            .unwrap()
+            .with_authorization("bearer token".to_owned());
+
+        let fetched = client.fetch(&format!("{}/same", url)).await.unwrap();
+        assert_eq!(fetched.body, b"bearer token");
+        let fetched = client.fetch(&format!("{}/other", url)).await.unwrap();
+        assert_eq!(fetched.body, b"anonymous");
+    }
+
+    static FLAKY_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn fetch_retries_only_transient_failures() {
+        let url = serve(|request| match request.path.as_str() {
+            "/flaky" if FLAKY_REQUESTS.fetch_add(1, Ordering::SeqCst) == 0 => status(503),
+            "/flaky" => ok("image"),
+            "/throttled" => status(429),
+            _ => status(404),
+        })
+        .await;
+        let client = local_client(1024);
+
+        let mut retries = vec![];
+        let fetched = client
+            .fetch_reporting(&format!("{}/flaky", url), |x| retries.push(x.clone()))
+            .await;
+        assert_eq!(fetched.unwrap().body, b"image");
+        assert_eq!(retries.len(), 1);
+
+        let mut retries = vec![];
+        let fetched = client
+            .fetch_reporting(&format!("{}/throttled", url), |x| retries.push(x.clone()))
+            .await;
+        assert_eq!(fetched.err(), Some(ErrorCode::UnexpectedStatus(429)));
+        assert_eq!(retries.len(), FetchOptions::default().max_attempts - 1);
+
+        let mut retries = vec![];
+        let fetched = client
+            .fetch_reporting(&format!("{}/missing", url), |x| retries.push(x.clone()))
+            .await;
+        assert_eq!(fetched.err(), Some(ErrorCode::UnexpectedStatus(404)));
+        assert!(retries.is_empty());
+    }
+
+    static LIMITED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn fetch_waits_for_the_rate_limit_reset() {
+        let url = serve(|_| {
+            if LIMITED_REQUESTS.fetch_add(1, Ordering::SeqCst) == 0 {
+                concat!(
+                    "HTTP/1.1 429 Too Many Requests\r\n",
+                    "X-Ratelimit-Reset: 1\r\n",
+                    "Content-Length: 0\r\n",
+                    "Connection: close\r\n\r\n"
+                )
+                .to_owned()
+            } else {
+                ok("listing")
+            }
+        })
+        .await;
+        let client = local_client(1024).with_rate_limit(spawn_rate_limit());
+
+        let mut events = vec![];
+        let fetched = client
+            .fetch_reporting(&format!("{}/search.json", url), |x| events.push(x.clone()))
+            .await;
+        assert_eq!(fetched.unwrap().body, b"listing");
+        assert!(events.iter().any(|x| match x {
+            FetchEvent::RateLimited { wait_ms, .. } => *wait_ms > 0,
+            _ => false,
+        }));
+    }
+
+    #[quickcheck]
+    fn backoff_delay_grows_and_stays_capped(attempt: usize, jitter: f32) -> bool {
+        let options = FetchOptions::default();
+        let attempt = 1 + attempt % 64;
+        let delay = backoff_delay(&options, attempt, jitter);
+        let next = backoff_delay(&options, attempt + 1, jitter);
+        delay <= options.retry_max_delay && delay >= options.retry_base_delay / 2 && next >= delay
+    }
+
+    #[tokio::test]
+    async fn fetch_limits_response_size() {
+        let url = serve(|request| match request.path.as_str() {
+            "/length" => ok(&"x".repeat(2048)),
+            _ => format!(
+                "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}",
+                "x".repeat(2048)
+            ),
+        })
+        .await;
+        let client = local_client(1024);
+        for path in ["/length", "/stream"].iter() {
+            let fetched = client.fetch(&format!("{}{}", url, path)).await;
+            assert_eq!(fetched.err(), Some(ErrorCode::TooLarge(1024)), "{}", path);
+        }
+    }
+
+    fn segment(s: &str) -> String {
+        let s: String = s.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        format!("x{}", s)
+    }
+
+    #[test]
+    fn resolve_url_follows_rfc_3986_examples() {
+        let base = "http://a/b/c/d;p?q";
+        let examples = [
+            ("g:h", "g:h"),
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q#s"),
+            ("g#s", "http://a/b/c/g#s"),
+            (".", "http://a/b/c/"),
+            ("./", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../g", "http://a/g"),
+            ("../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("g/../h", "http://a/b/c/h"),
+        ];
+        for (reference, expected) in examples.iter() {
+            assert_eq!(
+                resolve_url(base, reference).as_deref(),
+                Some(*expected),
+                "{}",
+                reference
+            );
+        }
+    }
+
+    #[quickcheck]
+    fn resolve_url_keeps_absolute_locations(host: String, path: String) -> bool {
+        let location = format!("https://{}.com/{}", segment(&host), segment(&path));
+        resolve_url("http://example.com/a/b", &location) == Some(location)
+    }
+
+    #[quickcheck]
+    fn resolve_url_keeps_relative_locations_on_the_same_host(path: String) -> bool {
+        let path = segment(&path);
+        resolve_url("https://example.com/a/b.png", &path)
+            == Some(format!("https://example.com/a/{}", path))
+            && resolve_url("https://example.com/a/b.png", &format!("/{}", path))
+                == Some(format!("https://example.com/{}", path))
+    }
+
+    #[quickcheck]
+    fn only_redirect_statuses_are_redirects(status: u16) -> bool {
+        is_redirect(status) == [301, 302, 303, 307, 308].contains(&status)
+    }
+}
@@ -0,0 +1,159 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::filter::CandidateFilter;
+use crate::http::OnFetchEvent;
+
+#[derive(Debug, Error)]
+pub enum ErrorCode {
+    #[error("invalid query")]
+    InvalidQuery,
+    #[error("cannot list candidates: {0}")]
+    CannotList(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Candidate {
+    pub id: String,
+    // image to analyze, also the key of the dominant color cache
+    pub url: String,
+    // whatever the source knows about the image, returned as is
+    #[serde(flatten)]
+    pub metadata: Map<String, Value>,
+}
+
+// Order of the candidates
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    Relevance,
+    Hot,
+    Top,
+    New,
+    Comments,
+}
+
+impl Sort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sort::Relevance => "relevance",
+            Sort::Hot => "hot",
+            Sort::Top => "top",
+            Sort::New => "new",
+            Sort::Comments => "comments",
+        }
+    }
+}
+
+// Only candidates posted within the range
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeRange {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+    All,
+}
+
+impl TimeRange {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeRange::Hour => "hour",
+            TimeRange::Day => "day",
+            TimeRange::Week => "week",
+            TimeRange::Month => "month",
+            TimeRange::Year => "year",
+            TimeRange::All => "all",
+        }
+    }
+}
+
+// Which candidates a source lists first, as reddit does. Sources listing in a
+// fixed order ignore it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Listing {
+    pub sort: Sort,
+    pub time: TimeRange,
+}
+
+impl Default for Listing {
+    fn default() -> Listing {
+        Listing {
+            sort: Sort::Comments,
+            time: TimeRange::All,
+        }
+    }
+}
+
+pub struct Page {
+    pub candidates: Vec<Candidate>,
+    // cursor of the next page, None after the last one
+    pub next: Option<String>,
+}
+
+// Where searches find their candidate images. Pages are requested one at a
+// time until the search has enough candidates or the source runs out.
+#[async_trait]
+pub trait ImageSource: Send + Sync {
+    // name used in logs and progress messages
+    fn name(&self) -> &str;
+
+    // The query sent to the source, narrowed by the filters the source
+    // applies itself, so it lists fewer candidates the search drops. The
+    // search still filters every candidate.
+    fn narrow_query(&self, query: &str, _filter: &CandidateFilter) -> String {
+        query.to_owned()
+    }
+
+    // on_event receives retries and rate limit waits of the source requests
+    async fn next_page(
+        &self,
+        query: &str,
+        listing: &Listing,
+        cursor: Option<String>,
+        on_event: OnFetchEvent,
+    ) -> Result<Page, ErrorCode>;
+
+    // Pages of a subreddit in the listing order, without any query. Sources
+    // without subreddits have none to list.
+    async fn next_subreddit_page(
+        &self,
+        _subreddit: &str,
+        _listing: &Listing,
+        _cursor: Option<String>,
+        _on_event: OnFetchEvent,
+    ) -> Result<Page, ErrorCode> {
+        Err(ErrorCode::InvalidQuery)
+    }
+}
+
+// Sources a search can choose from by name
+#[derive(Clone)]
+pub struct ImageSources {
+    sources: Vec<Arc<dyn ImageSource>>,
+    default: String,
+}
+
+impl ImageSources {
+    pub fn new(default: String) -> ImageSources {
+        ImageSources {
+            sources: vec![],
+            default,
+        }
+    }
+
+    pub fn add(&mut self, source: Arc<dyn ImageSource>) {
+        self.sources.push(source);
+    }
+
+    // The default source when no name is given
+    pub fn get(&self, name: Option<&str>) -> Option<Arc<dyn ImageSource>> {
+        let name = name.unwrap_or(&self.default);
+        self.sources.iter().find(|x| x.name() == name).cloned()
+    }
+}
@@ -0,0 +1,142 @@
+use std::cmp::Ordering;
+
+pub type Point = [f32; 3];
+
+fn squared_distance(a: &Point, b: &Point) -> f32 {
+    let x = a[0] - b[0];
+    let y = a[1] - b[1];
+    let z = a[2] - b[2];
+    x * x + y * y + z * z
+}
+
+// Balanced tree stored in place: the median of each slice is its node, the
+// points before and after it are its subtrees, split on x, y and z in turn
+pub struct KdTree<T> {
+    points: Vec<(Point, T)>,
+}
+
+fn build<T>(points: &mut [(Point, T)], depth: usize) {
+    if points.len() <= 1 {
+        return;
+    }
+    let axis = depth % 3;
+    let middle = points.len() / 2;
+    points.select_nth_unstable_by(middle, |a, b| {
+        a.0[axis].partial_cmp(&b.0[axis]).unwrap_or(Ordering::Equal)
+    });
+    let (before, after) = points.split_at_mut(middle);
+    build(before, depth + 1);
+    build(&mut after[1..], depth + 1);
+}
+
+// Keeps the k smallest squared distances found so far, sorted
+fn push_nearest(nearest: &mut Vec<(f32, usize)>, k: usize, distance: f32, i: usize) {
+    if nearest.len() == k && nearest.last().map_or(false, |x| x.0 <= distance) {
+        return;
+    }
+    let position = nearest
+        .iter()
+        .position(|x| x.0 > distance)
+        .unwrap_or_else(|| nearest.len());
+    nearest.insert(position, (distance, i));
+    nearest.truncate(k);
+}
+
+fn search<T>(
+    points: &[(Point, T)],
+    offset: usize,
+    depth: usize,
+    target: &Point,
+    k: usize,
+    accept: &impl Fn(&T) -> bool,
+    nearest: &mut Vec<(f32, usize)>,
+) {
+    if points.is_empty() {
+        return;
+    }
+    let axis = depth % 3;
+    let middle = points.len() / 2;
+    let (point, item) = &points[middle];
+    if accept(item) {
+        push_nearest(nearest, k, squared_distance(point, target), offset + middle);
+    }
+
+    let difference = target[axis] - point[axis];
+    let before = (&points[..middle], offset);
+    let after = (&points[middle + 1..], offset + middle + 1);
+    let (near, far) = if difference < 0.0 {
+        (before, after)
+    } else {
+        (after, before)
+    };
+    search(near.0, near.1, depth + 1, target, k, accept, nearest);
+    // the other side can only be closer than the splitting plane
+    if nearest.len() < k || difference * difference < nearest.last().map_or(f32::MAX, |x| x.0) {
+        search(far.0, far.1, depth + 1, target, k, accept, nearest);
+    }
+}
+
+impl<T> KdTree<T> {
+    pub fn new(mut points: Vec<(Point, T)>) -> KdTree<T> {
+        build(&mut points, 0);
+        KdTree { points }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn into_points(self) -> Vec<(Point, T)> {
+        self.points
+    }
+
+    // Up to k items accepted by the filter, nearest first, with their
+    // euclidean distance to the target
+    pub fn nearest(&self, target: &Point, k: usize, accept: impl Fn(&T) -> bool) -> Vec<(f32, &T)> {
+        let mut nearest = Vec::with_capacity(k + 1);
+        if k > 0 {
+            search(&self.points, 0, 0, target, k, &accept, &mut nearest);
+        }
+        nearest
+            .into_iter()
+            .map(|(distance, i)| (distance.sqrt(), &self.points[i].1))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn to_point(x: &(i8, i8, i8)) -> Point {
+        [x.0 as f32, x.1 as f32, x.2 as f32]
+    }
+
+    #[quickcheck]
+    fn nearest_matches_brute_force(points: Vec<(i8, i8, i8)>, target: (i8, i8, i8), k: u8) -> bool {
+        let k = k as usize % 10;
+        let target = to_point(&target);
+        let points: Vec<(Point, usize)> = points.iter().map(to_point).zip(0..).collect();
+        let even = |i: &usize| i % 2 == 0;
+
+        let mut expected: Vec<f32> = points
+            .iter()
+            .filter(|x| even(&x.1))
+            .map(|x| squared_distance(&x.0, &target).sqrt())
+            .collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        expected.truncate(k);
+
+        let tree = KdTree::new(points.clone());
+        let nearest = tree.nearest(&target, k, even);
+        let distances: Vec<f32> = nearest.iter().map(|x| x.0).collect();
+        distances == expected
+            && nearest
+                .iter()
+                .all(|(distance, i)| squared_distance(&points[**i].0, &target).sqrt() == *distance)
+    }
+}
@@ -0,0 +1,32 @@
+#[cfg(test)]
+extern crate quickcheck;
+#[cfg(test)]
+#[macro_use(quickcheck)]
+extern crate quickcheck_macros;
+
+pub mod actors;
+pub mod color_index;
+pub mod color_names;
+pub mod color_parser;
+pub mod colors;
+pub mod config;
+pub mod corpus;
+pub mod fetch_policy;
+pub mod filter;
+pub mod formats;
+pub mod http;
+pub mod image_source;
+pub mod kd_tree;
+pub mod local_source;
+mod loggable;
+pub mod ord;
+pub mod query;
+pub mod ranking;
+pub mod reddit;
+pub mod reddit_api;
+pub mod sampling;
+pub mod search;
+#[cfg(test)]
+mod test_server;
+pub mod text;
+pub mod url_encoding;
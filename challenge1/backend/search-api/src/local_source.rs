@@ -0,0 +1,408 @@
+use async_trait::async_trait;
+use serde_json::{Map, Value};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::formats::has_image_extension;
+use crate::http::OnFetchEvent;
+use crate::image_source::{self, Candidate, ImageSource, Listing, Page};
+use crate::query::parse_query;
+use crate::text::get_words;
+use crate::url_encoding::{is_unreserved, percent_decode, percent_encode};
+
+// Local images are served, and cached, under this path
+pub const LOCAL_URL_PREFIX: &str = "/local";
+
+const PAGE_SIZE: usize = 100;
+
+// Extra tags of "photo.jpg" are read from "photo.jpg.tags", separated by
+// spaces, commas or new lines
+const TAGS_EXTENSION: &str = "tags";
+
+// Images of a local directory, identified by their path relative to it,
+// always with '/' separators
+#[derive(Clone, Debug)]
+pub struct LocalFiles {
+    root: PathBuf,
+}
+
+impl LocalFiles {
+    pub fn new(root: PathBuf) -> LocalFiles {
+        LocalFiles { root }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn url(&self, relative: &str) -> String {
+        let segments: Vec<String> = relative
+            .split('/')
+            .map(|x| percent_encode(x, is_unreserved))
+            .collect();
+        format!("{}/{}", LOCAL_URL_PREFIX, segments.join("/"))
+    }
+
+    // File of a url built by url, None for any other url
+    pub fn path(&self, url: &str) -> Option<PathBuf> {
+        let relative = url.strip_prefix(LOCAL_URL_PREFIX)?.strip_prefix('/')?;
+        let relative: Option<Vec<String>> = relative.split('/').map(percent_decode).collect();
+        self.resolve(&relative?.join("/"))
+    }
+
+    // Only plain names are accepted, so the path never leaves the root
+    pub fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for segment in relative.split('/') {
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if name == segment => path.push(name),
+                _ => return None,
+            }
+        }
+        Some(path)
+    }
+}
+
+// Modification time and size, the size catches changes within the time
+// resolution of the file system
+type Stamp = Option<(SystemTime, u64)>;
+
+fn get_stamp(path: &Path) -> Stamp {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// Images below the root with their tags, as walked once. Adding or removing
+// entries changes the modification time of their directory, and editing
+// tags the one of the tags file, so checking those is enough to know whether
+// the walk is still valid.
+struct Snapshot {
+    // sorted by relative path
+    images: Vec<(String, Vec<String>)>,
+    stamps: Vec<(PathBuf, Stamp)>,
+}
+
+impl Snapshot {
+    fn is_fresh(&self) -> bool {
+        self.stamps
+            .iter()
+            .all(|(path, stamp)| get_stamp(path) == *stamp)
+    }
+}
+
+// Words of the directories and file name, plus the ones of the tags file
+fn get_tags(files: &LocalFiles, relative: &str, stamps: &mut Vec<(PathBuf, Stamp)>) -> Vec<String> {
+    let mut tags = get_words(relative);
+    let tags_file = files.resolve(&format!("{}.{}", relative, TAGS_EXTENSION));
+    if let Some(path) = tags_file {
+        if let Ok(txt) = std::fs::read_to_string(&path) {
+            tags.extend(get_words(&txt));
+            stamps.push((path.clone(), get_stamp(&path)));
+        }
+    }
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// Every image below the root, sorted. Hidden entries and symbolic links are
+// skipped.
+fn walk(files: &LocalFiles) -> std::io::Result<Snapshot> {
+    let mut relatives = vec![];
+    let mut stamps = vec![];
+    let mut directories = vec![(files.root().to_owned(), String::new())];
+    let mut first = true;
+    while let Some((directory, prefix)) = directories.pop() {
+        // read before the entries, so a change while reading them is seen
+        let stamp = get_stamp(&directory);
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            // the root must exist, unreadable subdirectories are ignored
+            Err(err) if first => return Err(err),
+            Err(_) => continue,
+        };
+        first = false;
+        stamps.push((directory, stamp));
+        for entry in entries.filter_map(|x| x.ok()) {
+            let name = match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+            let relative = format!("{}{}", prefix, name);
+            match entry.file_type() {
+                Ok(x) if x.is_dir() => directories.push((entry.path(), format!("{}/", relative))),
+                Ok(x) if x.is_file() && has_image_extension(&name) => relatives.push(relative),
+                _ => {}
+            }
+        }
+    }
+    relatives.sort();
+    let images = relatives
+        .into_iter()
+        .map(|relative| {
+            let tags = get_tags(files, &relative, &mut stamps);
+            (relative, tags)
+        })
+        .collect();
+    Ok(Snapshot { images, stamps })
+}
+
+// Images after the cursor matching the query, and the cursor of the next page
+fn list_page(
+    files: &LocalFiles,
+    snapshot: &Snapshot,
+    query: &str,
+    cursor: Option<&str>,
+) -> (Vec<Candidate>, Option<String>) {
+    let query = parse_query(query);
+    let start = match cursor {
+        None => 0,
+        Some(cursor) => match snapshot
+            .images
+            .binary_search_by(|(x, _)| x.as_str().cmp(cursor))
+        {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        },
+    };
+    let mut candidates = vec![];
+    for (relative, tags) in snapshot.images[start..].iter() {
+        if !query.matches(tags) {
+            continue;
+        }
+        if candidates.len() == PAGE_SIZE {
+            let next = candidates.last().map(|x: &Candidate| x.id.clone());
+            return (candidates, next);
+        }
+        let mut metadata = Map::new();
+        metadata.insert("tags".to_owned(), Value::from(tags.clone()));
+        candidates.push(Candidate {
+            url: files.url(relative),
+            id: relative.clone(),
+            metadata,
+        });
+    }
+    (candidates, None)
+}
+
+// Searches a local directory, without any network access. The walk is kept
+// between pages and searches, and done again once the directories or tags
+// changed, so new images are found without restarting.
+pub struct LocalSource {
+    files: LocalFiles,
+    snapshot: Arc<Mutex<Option<Arc<Snapshot>>>>,
+}
+
+impl LocalSource {
+    pub fn new(files: LocalFiles) -> LocalSource {
+        LocalSource {
+            files,
+            snapshot: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+// The cached walk while it is fresh, a new one otherwise
+fn get_snapshot(
+    files: &LocalFiles,
+    cache: &Mutex<Option<Arc<Snapshot>>>,
+) -> std::io::Result<Arc<Snapshot>> {
+    let mut cache = cache.lock().unwrap_or_else(|x| x.into_inner());
+    match &*cache {
+        Some(snapshot) if snapshot.is_fresh() => Ok(snapshot.clone()),
+        _ => {
+            let snapshot = Arc::new(walk(files)?);
+            *cache = Some(snapshot.clone());
+            Ok(snapshot)
+        }
+    }
+}
+
+#[async_trait]
+impl ImageSource for LocalSource {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn next_page(
+        &self,
+        query: &str,
+        _: &Listing,
+        cursor: Option<String>,
+        _: OnFetchEvent,
+    ) -> Result<Page, image_source::ErrorCode> {
+        let files = self.files.clone();
+        let cache = self.snapshot.clone();
+        let query = query.to_owned();
+        let page = tokio::task::spawn_blocking(move || {
+            let snapshot = get_snapshot(&files, &cache)?;
+            Ok::<_, std::io::Error>(list_page(&files, &snapshot, &query, cursor.as_deref()))
+        })
+        .await
+        .map_err(|x| image_source::ErrorCode::CannotList(x.to_string()))?;
+        let (candidates, next) =
+            page.map_err(|x| image_source::ErrorCode::CannotList(x.to_string()))?;
+        Ok(Page { candidates, next })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Empty files are enough to list them
+    fn create_files(name: &str, files: &[(&str, &str)]) -> LocalFiles {
+        let root = std::env::temp_dir().join(format!("search-api-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (relative, content) in files {
+            let path = root.join(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        LocalFiles::new(root)
+    }
+
+    fn ids(candidates: &[Candidate]) -> Vec<&str> {
+        candidates.iter().map(|x| x.id.as_str()).collect()
+    }
+
+    fn list(
+        files: &LocalFiles,
+        query: &str,
+        cursor: Option<&str>,
+    ) -> (Vec<Candidate>, Option<String>) {
+        list_page(files, &walk(files).unwrap(), query, cursor)
+    }
+
+    #[test]
+    fn query_matches_names_and_tags() {
+        let files = create_files(
+            "tags",
+            &[
+                ("cars/Red Ferrari.jpg", ""),
+                ("cars/blue-van.png", ""),
+                ("cars/blue-van.png.tags", "Sunset, beach"),
+                ("cars/notes.txt", ""),
+                ("cars/.hidden.jpg", ""),
+                ("café/menu.webp", ""),
+            ],
+        );
+        let list = |query| list(&files, query, None);
+        assert_eq!(
+            ids(&list("").0),
+            vec![
+                "café/menu.webp",
+                "cars/Red Ferrari.jpg",
+                "cars/blue-van.png"
+            ]
+        );
+        assert_eq!(ids(&list("ferrari red").0), vec!["cars/Red Ferrari.jpg"]);
+        assert_eq!(ids(&list("SUNSET van").0), vec!["cars/blue-van.png"]);
+        assert_eq!(ids(&list("Café").0), vec!["café/menu.webp"]);
+        assert!(list("ferrari sunset").0.is_empty());
+        let _ = std::fs::remove_dir_all(files.root());
+    }
+
+    #[test]
+    fn query_operators_are_followed() {
+        let files = create_files(
+            "operators",
+            &[("cars/red-ferrari.jpg", ""), ("cars/blue-van.png", "")],
+        );
+        let list = |query| list(&files, query, None);
+        assert_eq!(
+            ids(&list("ferrari OR van").0),
+            vec!["cars/blue-van.png", "cars/red-ferrari.jpg"]
+        );
+        assert_eq!(ids(&list("cars -blue").0), vec!["cars/red-ferrari.jpg"]);
+        assert_eq!(ids(&list("cars NOT red").0), vec!["cars/blue-van.png"]);
+        assert!(list("\"ferrari red\"").0.is_empty());
+        let _ = std::fs::remove_dir_all(files.root());
+    }
+
+    #[test]
+    fn pages_continue_after_the_cursor() {
+        let names: Vec<String> = (0..PAGE_SIZE + 5)
+            .map(|i| format!("{:04}.png", i))
+            .collect();
+        let files: Vec<(&str, &str)> = names.iter().map(|x| (x.as_str(), "")).collect();
+        let files = create_files("pages", &files);
+        let (first, next) = list(&files, "", None);
+        assert_eq!(first.len(), PAGE_SIZE);
+        assert_eq!(next.as_deref(), Some(names[PAGE_SIZE - 1].as_str()));
+        let (second, next) = list(&files, "", next.as_deref());
+        assert_eq!(
+            ids(&second),
+            names[PAGE_SIZE..]
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(next, None);
+        let _ = std::fs::remove_dir_all(files.root());
+    }
+
+    #[test]
+    fn walks_are_kept_until_files_change() {
+        let files = create_files("snapshot", &[("cars/red.jpg", ""), ("blue.png", "")]);
+        let cache = Mutex::new(None);
+        let first = get_snapshot(&files, &cache).unwrap();
+        assert!(Arc::ptr_eq(&first, &get_snapshot(&files, &cache).unwrap()));
+
+        std::fs::write(files.root().join("cars/green.jpg"), "").unwrap();
+        let second = get_snapshot(&files, &cache).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(
+            ids(&list_page(&files, &second, "", None).0),
+            vec!["blue.png", "cars/green.jpg", "cars/red.jpg"]
+        );
+
+        std::fs::write(files.root().join("blue.png.tags"), "sky").unwrap();
+        let third = get_snapshot(&files, &cache).unwrap();
+        assert_eq!(
+            ids(&list_page(&files, &third, "sky", None).0),
+            vec!["blue.png"]
+        );
+        std::fs::write(files.root().join("blue.png.tags"), "sea, waves").unwrap();
+        let fourth = get_snapshot(&files, &cache).unwrap();
+        assert_eq!(
+            ids(&list_page(&files, &fourth, "waves", None).0),
+            vec!["blue.png"]
+        );
+        let _ = std::fs::remove_dir_all(files.root());
+    }
+
+    #[quickcheck]
+    fn urls_lead_back_to_their_file(a: String, b: String) -> bool {
+        let files = LocalFiles::new(PathBuf::from("/images"));
+        let (a, b) = (a.replace('/', ""), b.replace('/', ""));
+        let relative = format!("{}/{}", a, b);
+        match files.resolve(&relative) {
+            None => true,
+            Some(path) => files.path(&files.url(&relative)) == Some(path),
+        }
+    }
+
+    #[test]
+    fn paths_never_leave_the_root() {
+        let files = LocalFiles::new(PathBuf::from("/images"));
+        assert_eq!(
+            files.path("/local/cars/red%20car.jpg"),
+            Some(PathBuf::from("/images/cars/red car.jpg"))
+        );
+        for url in [
+            "/local/../etc/passwd",
+            "/local/cars/%2E%2E/%2E%2E/etc/passwd",
+            "/local//etc/passwd",
+            "/local/cars/./a.jpg",
+            "https://i.imgur.com/local/a.jpg",
+            "/localx/a.jpg",
+        ]
+        .iter()
+        {
+            assert_eq!(files.path(url), None, "{}", url);
+        }
+    }
+}
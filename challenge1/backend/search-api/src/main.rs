@@ -1,88 +1,626 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
-#[cfg(test)]
-extern crate quickcheck;
-#[cfg(test)]
-#[macro_use(quickcheck)]
-extern crate quickcheck_macros;
-
 use async_channel::Sender;
+use bytes::Buf;
 use futures::StreamExt;
-use palette::{IntoColor, Srgb};
+use palette::Lab;
 use serde::Deserialize;
 use std::convert::Infallible;
+use std::sync::Arc;
 use thiserror::Error;
+use warp::multipart::FormData;
+use warp::path::Tail;
 use warp::Filter;
 
-mod actors;
-mod colors;
-mod loggable;
-mod ord;
-mod reddit;
-use actors::dominant_color::{spawn_dominant_color, DominantColorDistanceMessage};
-use actors::dominant_color_cache::{spawn_dominant_color_cache, DominantColorCacheMessage};
-use reddit::get_reddit_with_progress;
+use search_api::actors::color_index::spawn_color_index;
+use search_api::actors::crawler::{spawn_crawler, CrawlerMessage};
+use search_api::actors::dominant_color::{
+    self, get_image_dominant_colors, get_url_dominant_colors, spawn_dominant_color,
+};
+use search_api::actors::dominant_color_cache::spawn_dominant_color_cache;
+use search_api::actors::rate_limit::spawn_rate_limit;
+use search_api::color_index::ColorIndex;
+use search_api::color_names::describe_palette;
+use search_api::color_parser::{
+    self, parse_color, parse_hsl, parse_lab, parse_layout, parse_palette, parse_rgb,
+};
+use search_api::colors::{PaletteColor, Target};
+use search_api::config::Config;
+use search_api::corpus::CorpusSource;
+use search_api::filter::CandidateFilter;
+use search_api::formats::get_capabilities;
+use search_api::formats::get_mime_type;
+use search_api::http::Client;
+use search_api::image_source::{ImageSource, ImageSources, Listing, Sort, TimeRange};
+use search_api::local_source::{LocalFiles, LocalSource, LOCAL_URL_PREFIX};
+use search_api::ranking::RankingWeights;
+use search_api::reddit::RedditSource;
+use search_api::reddit_api::RedditApi;
+use search_api::sampling::SamplingOptions;
+use search_api::search::{get_search_result, search_with_progress, SearchActors, SearchQuery};
 
 type BoxedResult = Result<Box<dyn warp::reply::Reply>, Infallible>;
 
+const MAX_EXAMPLE_IMAGE_SIZE: u64 = 10 * 1024 * 1024;
+
 #[derive(Deserialize)]
 pub struct SearchQueryString {
     q: Option<String>,
-    r: Option<f32>,
-    g: Option<f32>,
-    b: Option<f32>,
+    r: Option<String>,
+    g: Option<String>,
+    b: Option<String>,
+    color: Option<String>,
+    hsl: Option<String>,
+    lab: Option<String>,
+    palette: Option<String>,
+    layout: Option<String>,
+    source: Option<String>,
+}
+
+fn single_color(color: Lab) -> Target {
+    Target::Palette(vec![PaletteColor { color, weight: 1.0 }])
+}
+
+fn get_target(query_string: &SearchQueryString) -> Result<Target, color_parser::ErrorCode> {
+    let rgb = match (&query_string.r, &query_string.g, &query_string.b) {
+        (None, None, None) => None,
+        (r, g, b) => Some((
+            r.as_deref().unwrap_or(""),
+            g.as_deref().unwrap_or(""),
+            b.as_deref().unwrap_or(""),
+        )),
+    };
+    let forms = [
+        query_string.palette.is_some(),
+        query_string.layout.is_some(),
+        query_string.color.is_some(),
+        query_string.hsl.is_some(),
+        query_string.lab.is_some(),
+        rgb.is_some(),
+    ];
+    if forms.iter().filter(|x| **x).count() > 1 {
+        return Err(color_parser::ErrorCode::AmbiguousColor);
+    }
+
+    if let Some(palette) = &query_string.palette {
+        parse_palette(palette).map(Target::Palette)
+    } else if let Some(layout) = &query_string.layout {
+        parse_layout(layout).map(Target::Layout)
+    } else if let Some(color) = &query_string.color {
+        parse_color(color).map(single_color)
+    } else if let Some(hsl) = &query_string.hsl {
+        parse_hsl(hsl).map(single_color)
+    } else if let Some(lab) = &query_string.lab {
+        parse_lab(lab).map(single_color)
+    } else if let Some((r, g, b)) = rgb {
+        parse_rgb(r, g, b).map(single_color)
+    } else {
+        Err(color_parser::ErrorCode::MissingColor)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExampleQueryString {
+    q: Option<String>,
+    url: Option<String>,
+    source: Option<String>,
+}
+
+// Read from the query string of every search endpoint
+#[derive(Deserialize)]
+pub struct SearchOptionsQueryString {
+    // skip the color index and search the source
+    fresh: Option<bool>,
+    color_weight: Option<f32>,
+    text_weight: Option<f32>,
+    // relevance, hot, top, new or comments
+    sort: Option<Sort>,
+    // hour, day, week, month, year or all
+    t: Option<TimeRange>,
+    // false excludes nsfw posts
+    nsfw: Option<bool>,
+    min_score: Option<i64>,
+    min_comments: Option<u64>,
+    // unix seconds
+    since: Option<f64>,
+    until: Option<f64>,
+    subreddit: Option<String>,
+    domain: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PaletteQueryString {
+    url: Option<String>,
 }
 
 #[derive(Debug, Error)]
 pub enum ErrorCode {
     #[error("error on search progress")]
     Error,
+    #[error("missing query, use q")]
+    MissingQuery,
+    #[error("missing image url, use url")]
+    MissingUrl,
+    #[error("missing image, upload it as the \"image\" multipart field")]
+    MissingImage,
+    #[error("cannot extract colors from image")]
+    InvalidImage,
+    #[error("{0}")]
+    CannotDownload(dominant_color::ErrorCode),
+    #[error("{0}")]
+    InvalidColor(#[from] color_parser::ErrorCode),
+    #[error("unknown image source")]
+    UnknownSource,
+    #[error("weights must be finite and not negative, use color_weight and text_weight")]
+    InvalidWeights,
+}
+
+fn bad_request(err: impl std::fmt::Display) -> BoxedResult {
+    Ok(Box::new(warp::reply::with_status(
+        err.to_string(),
+        warp::http::StatusCode::BAD_REQUEST,
+    )))
+}
+
+fn get_search_query(
+    q: Option<String>,
+    target: Result<Target, ErrorCode>,
+    options: &SearchOptionsQueryString,
+) -> Result<SearchQuery, ErrorCode> {
+    let target = target?;
+    let q = q.ok_or(ErrorCode::MissingQuery)?;
+    let default = RankingWeights::default();
+    let weights = RankingWeights {
+        color: options.color_weight.unwrap_or(default.color),
+        text: options.text_weight.unwrap_or(default.text),
+    };
+    if !weights.is_valid() {
+        return Err(ErrorCode::InvalidWeights);
+    }
+    let default = Listing::default();
+    let listing = Listing {
+        sort: options.sort.unwrap_or(default.sort),
+        time: options.t.unwrap_or(default.time),
+    };
+    Ok(SearchQuery {
+        q,
+        target,
+        fresh: options.fresh.unwrap_or(false),
+        weights,
+        listing,
+        filter: CandidateFilter {
+            nsfw: options.nsfw.unwrap_or(true),
+            min_score: options.min_score,
+            min_comments: options.min_comments,
+            since: options.since,
+            until: options.until,
+            subreddit: options.subreddit.clone(),
+            domain: options.domain.clone(),
+        },
+    })
 }
 
 async fn search(
     query_string: SearchQueryString,
-    cache_actor: Sender<DominantColorCacheMessage>,
-    dominant_color_actor: Sender<DominantColorDistanceMessage>,
+    options: SearchOptionsQueryString,
+    actors: SearchActors,
+    sources: ImageSources,
 ) -> BoxedResult {
     let str_to_sse_data = |x| match Some(x) {
         Some(x) => Ok(warp::sse::data(x)),
         None => Err(ErrorCode::Error),
     };
-    match (
-        query_string.q,
-        query_string.r,
-        query_string.g,
-        query_string.b,
-    ) {
-        (Some(query), Some(r), Some(g), Some(b)) => {
-            let desired_color = Srgb::new(r, g, b).into_lab();
-            let progress =
-                get_reddit_with_progress(query, desired_color, cache_actor, dominant_color_actor);
-            let progress = progress.map(str_to_sse_data);
-            let progress = warp::sse::reply(progress);
-            Ok(Box::new(progress))
+    let target = match get_target(&query_string) {
+        Ok(target) => target,
+        Err(err) => return bad_request(err),
+    };
+    let source = match sources.get(query_string.source.as_deref()) {
+        Some(source) => source,
+        None => return bad_request(ErrorCode::UnknownSource),
+    };
+    let query = match get_search_query(query_string.q, Ok(target), &options) {
+        Ok(query) => query,
+        Err(err) => return bad_request(err),
+    };
+    let progress = search_with_progress(source, query, actors);
+    let progress = progress.map(str_to_sse_data);
+    let progress = warp::sse::reply(progress);
+    Ok(Box::new(progress))
+}
+
+async fn search_result(
+    query: Result<SearchQuery, ErrorCode>,
+    actors: SearchActors,
+    source: Option<Arc<dyn ImageSource>>,
+) -> BoxedResult {
+    let source = match source {
+        Some(source) => source,
+        None => return bad_request(ErrorCode::UnknownSource),
+    };
+    let query = match query {
+        Ok(query) => query,
+        Err(err) => return bad_request(err),
+    };
+    match get_search_result(source, query, actors).await {
+        Ok(result) => Ok(Box::new(warp::reply::json(&result))),
+        Err(_) => Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+async fn search_json(
+    query_string: SearchQueryString,
+    options: SearchOptionsQueryString,
+    actors: SearchActors,
+    sources: ImageSources,
+) -> BoxedResult {
+    let target = get_target(&query_string).map_err(ErrorCode::InvalidColor);
+    search_result(
+        get_search_query(query_string.q, target, &options),
+        actors,
+        sources.get(query_string.source.as_deref()),
+    )
+    .await
+}
+
+async fn search_example_url(
+    query_string: ExampleQueryString,
+    options: SearchOptionsQueryString,
+    client: Client,
+    sampling: SamplingOptions,
+    actors: SearchActors,
+    sources: ImageSources,
+) -> BoxedResult {
+    let target = match query_string.url {
+        Some(url) => match get_url_dominant_colors(url, &client, &sampling, |_| {}).await {
+            Ok(Some(colors)) => Ok(Target::Palette(colors.palette)),
+            Ok(None) => Err(ErrorCode::InvalidImage),
+            Err(err) => Err(ErrorCode::CannotDownload(err)),
+        },
+        None => Err(ErrorCode::MissingUrl),
+    };
+    search_result(
+        get_search_query(query_string.q, target, &options),
+        actors,
+        sources.get(query_string.source.as_deref()),
+    )
+    .await
+}
+
+async fn read_image_part(mut form: FormData) -> Option<Vec<u8>> {
+    while let Some(Ok(mut part)) = form.next().await {
+        if part.name() == "image" {
+            let mut data = Vec::new();
+            while let Some(chunk) = part.data().await {
+                data.extend_from_slice(chunk.ok()?.bytes());
+            }
+            return Some(data);
         }
-        _ => Ok(Box::new(warp::http::StatusCode::BAD_REQUEST)),
+    }
+    None
+}
+
+async fn search_example_upload(
+    query_string: ExampleQueryString,
+    options: SearchOptionsQueryString,
+    form: FormData,
+    sampling: SamplingOptions,
+    actors: SearchActors,
+    sources: ImageSources,
+) -> BoxedResult {
+    let target = match read_image_part(form).await {
+        Some(data) => get_image_dominant_colors(&data, &sampling)
+            .map(|x| Target::Palette(x.palette))
+            .ok_or(ErrorCode::InvalidImage),
+        None => Err(ErrorCode::MissingImage),
+    };
+    search_result(
+        get_search_query(query_string.q, target, &options),
+        actors,
+        sources.get(query_string.source.as_deref()),
+    )
+    .await
+}
+
+// GET with the url of the example image, POST with the image uploaded as
+// the "image" multipart field
+fn search_example_endpoints(
+    client: Client,
+    sampling: SamplingOptions,
+    actors: SearchActors,
+    sources: ImageSources,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let client = warp::any().map(move || client.clone());
+    let sampling = warp::any().map(move || sampling);
+    let actors = warp::any().map(move || actors.clone());
+    let sources = warp::any().map(move || sources.clone());
+    let url_endpoint = warp::get()
+        .and(warp::path!("search" / "example"))
+        .and(warp::query::<ExampleQueryString>())
+        .and(warp::query::<SearchOptionsQueryString>())
+        .and(client)
+        .and(sampling.clone())
+        .and(actors.clone())
+        .and(sources.clone())
+        .and_then(search_example_url);
+    let upload_endpoint = warp::post()
+        .and(warp::path!("search" / "example"))
+        .and(warp::query::<ExampleQueryString>())
+        .and(warp::query::<SearchOptionsQueryString>())
+        .and(warp::multipart::form().max_length(MAX_EXAMPLE_IMAGE_SIZE))
+        .and(sampling)
+        .and(actors)
+        .and(sources)
+        .and_then(search_example_upload);
+    url_endpoint.or(upload_endpoint)
+}
+
+async fn get_palette(
+    query_string: PaletteQueryString,
+    client: Client,
+    sampling: SamplingOptions,
+) -> BoxedResult {
+    let url = match query_string.url {
+        Some(url) => url,
+        None => return bad_request(ErrorCode::MissingUrl),
+    };
+    match get_url_dominant_colors(url, &client, &sampling, |_| {}).await {
+        Ok(Some(dominant_colors)) => Ok(Box::new(warp::reply::json(&describe_palette(
+            &dominant_colors.palette,
+        )))),
+        _ => bad_request(ErrorCode::InvalidImage),
+    }
+}
+
+async fn get_crawler_status(crawler: Sender<CrawlerMessage>) -> BoxedResult {
+    let (w, s) = oneshot::channel();
+    if crawler.send(CrawlerMessage::Status(w)).await.is_err() {
+        return Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+    }
+    match s.await {
+        Ok(status) => Ok(Box::new(warp::reply::json(&status))),
+        Err(_) => Ok(Box::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR)),
+    }
+}
+
+async fn get_local_image(tail: Tail, local_files: Option<LocalFiles>) -> BoxedResult {
+    let url = format!("{}/{}", LOCAL_URL_PREFIX, tail.as_str());
+    let data = match local_files.and_then(|x| x.path(&url)) {
+        Some(path) => tokio::fs::read(path).await.ok(),
+        None => None,
+    };
+    match data.as_ref().and_then(|x| get_mime_type(x)) {
+        Some(mime_type) => Ok(Box::new(warp::reply::with_header(
+            data.unwrap_or_default(),
+            "Content-Type",
+            mime_type,
+        ))),
+        None => Ok(Box::new(warp::http::StatusCode::NOT_FOUND)),
     }
 }
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
+    let config = Config::from_env();
+
+    let sampling_options = config.sampling;
+    let sampling = warp::any().map(move || sampling_options);
 
-    let w = spawn_dominant_color_cache();
-    let cache_actor = warp::any().map(move || w.clone());
+    let http_client =
+        Client::new(config.fetch, config.fetch_policy).expect("cannot build http client");
+    let w = http_client.clone();
+    let client = warp::any().map(move || w.clone());
+    // listing requests of every search share the reddit rate limit
+    let reddit = RedditApi::new(
+        http_client.with_rate_limit(spawn_rate_limit()),
+        config.reddit,
+    );
+    let local_files = config.local_dir.map(LocalFiles::new);
+    let mut w = ImageSources::new(config.default_source);
+    w.add(Arc::new(RedditSource::new(reddit)));
+    if let Some(local_files) = &local_files {
+        w.add(Arc::new(LocalSource::new(local_files.clone())));
+    }
+    w.add(Arc::new(CorpusSource::new(config.corpus)));
+    let crawler_source = w
+        .get(config.crawler.source.as_deref())
+        .expect("unknown crawler source");
+    let example_sources = w.clone();
+    let sources = warp::any().map(move || w.clone());
 
-    let w = spawn_dominant_color();
-    let dominant_color_actor = warp::any().map(move || w.clone());
+    let color_index = ColorIndex::open(config.color_index).expect("cannot read color index");
+    let w = SearchActors {
+        cache: spawn_dominant_color_cache(),
+        dominant_color: spawn_dominant_color(
+            http_client.clone(),
+            config.sampling,
+            local_files.clone(),
+        ),
+        index: spawn_color_index(color_index),
+    };
+    // runs in the background, the endpoint only reports its progress
+    let crawler = spawn_crawler(crawler_source, config.crawler, w.clone());
+    let search_example_endpoints =
+        search_example_endpoints(http_client, config.sampling, w.clone(), example_sources);
+    let actors = warp::any().map(move || w.clone());
+    let w = crawler;
+    let crawler = warp::any().map(move || w.clone());
 
     let search_endpoint = warp::get()
         .and(warp::path("search"))
+        .and(warp::path::end())
         .and(warp::query::<SearchQueryString>())
-        .and(cache_actor.clone())
-        .and(dominant_color_actor.clone())
+        .and(warp::query::<SearchOptionsQueryString>())
+        .and(actors.clone())
+        .and(sources.clone())
         .and_then(search);
-    warp::serve(search_endpoint)
-        .run(([127, 0, 0, 1], 8000))
-        .await
+    let search_json_endpoint = warp::get()
+        .and(warp::path!("search" / "json"))
+        .and(warp::query::<SearchQueryString>())
+        .and(warp::query::<SearchOptionsQueryString>())
+        .and(actors.clone())
+        .and(sources.clone())
+        .and_then(search_json);
+    let palette_endpoint = warp::get()
+        .and(warp::path!("palette"))
+        .and(warp::query::<PaletteQueryString>())
+        .and(client.clone())
+        .and(sampling.clone())
+        .and_then(get_palette);
+    let w = local_files;
+    let local_files = warp::any().map(move || w.clone());
+    let local_endpoint = warp::get()
+        .and(warp::path("local"))
+        .and(warp::path::tail())
+        .and(local_files)
+        .and_then(get_local_image);
+    let crawler_endpoint = warp::get()
+        .and(warp::path!("admin" / "crawler"))
+        .and(crawler)
+        .and_then(get_crawler_status);
+    let capabilities_endpoint = warp::get()
+        .and(warp::path!("capabilities"))
+        .map(|| warp::reply::json(&get_capabilities()));
+    warp::serve(
+        search_endpoint
+            .or(search_json_endpoint)
+            .or(search_example_endpoints)
+            .or(palette_endpoint)
+            .or(capabilities_endpoint)
+            .or(local_endpoint)
+            .or(crawler_endpoint),
+    )
+    .run(([127, 0, 0, 1], 8000))
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_trait::async_trait;
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+    use search_api::http::{FetchOptions, OnFetchEvent};
+    use search_api::image_source::{self, Page};
+
+    const BOUNDARY: &str = "example-boundary";
+
+    // Source without candidates, searches end with the index
+    struct EmptySource;
+
+    #[async_trait]
+    impl ImageSource for EmptySource {
+        fn name(&self) -> &str {
+            "empty"
+        }
+
+        async fn next_page(
+            &self,
+            _: &str,
+            _: &Listing,
+            _: Option<String>,
+            _: OnFetchEvent,
+        ) -> Result<Page, image_source::ErrorCode> {
+            Ok(Page {
+                candidates: vec![],
+                next: None,
+            })
+        }
+    }
+
+    fn endpoints() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        let client = Client::new(FetchOptions::default(), Default::default()).unwrap();
+        let mut sources = ImageSources::new("empty".to_owned());
+        sources.add(Arc::new(EmptySource));
+        let actors = SearchActors {
+            cache: async_channel::unbounded().0,
+            dominant_color: async_channel::unbounded().0,
+            index: spawn_color_index(ColorIndex::new()),
+        };
+        search_example_endpoints(client, SamplingOptions::default(), actors, sources)
+    }
+
+    fn png() -> Vec<u8> {
+        let img = RgbImage::from_pixel(64, 64, Rgb([200, 30, 30]));
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut data, ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    fn multipart(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            concat!(
+                "--{}\r\n",
+                "Content-Disposition: form-data; name=\"{}\"; filename=\"a.png\"\r\n",
+                "Content-Type: image/png\r\n\r\n"
+            ),
+            BOUNDARY, name
+        )
+        .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    async fn upload(body: Vec<u8>) -> warp::http::Response<bytes::Bytes> {
+        warp::test::request()
+            .method("POST")
+            .path("/search/example?q=car")
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(body)
+            .reply(&endpoints())
+            .await
+    }
+
+    #[tokio::test]
+    async fn example_uploads_are_searched() {
+        let response = upload(multipart("image", &png())).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), br#"{"images":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn example_uploads_need_an_image_part() {
+        let response = upload(multipart("file", &png())).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            response.body().as_ref(),
+            ErrorCode::MissingImage.to_string().as_bytes()
+        );
+
+        let response = upload(multipart("image", b"not an image")).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            response.body().as_ref(),
+            ErrorCode::InvalidImage.to_string().as_bytes()
+        );
+
+        let oversized = vec![0u8; MAX_EXAMPLE_IMAGE_SIZE as usize];
+        let response = upload(multipart("image", &oversized)).await;
+        assert_eq!(response.status(), 413);
+    }
+
+    #[tokio::test]
+    async fn example_urls_must_be_fetchable() {
+        let get = |path: &'static str| async move {
+            warp::test::request().path(path).reply(&endpoints()).await
+        };
+        let response = get("/search/example?q=car").await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            response.body().as_ref(),
+            ErrorCode::MissingUrl.to_string().as_bytes()
+        );
+
+        let response = get("/search/example?q=car&url=file:///etc/passwd").await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            response.body().as_ref(),
+            b"cannot download image: scheme file is not allowed"
+        );
+    }
 }
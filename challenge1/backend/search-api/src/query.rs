@@ -0,0 +1,380 @@
+use crate::text::get_words;
+
+// Qualifiers reddit understands and we pass through, any other "name:value"
+// is searched as plain words
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    Author,
+    Site,
+    Subreddit,
+    Title,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        match name.to_lowercase().as_str() {
+            "author" => Some(Field::Author),
+            "site" => Some(Field::Site),
+            "subreddit" => Some(Field::Subreddit),
+            "title" => Some(Field::Title),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Field::Author => "author",
+            Field::Site => "site",
+            Field::Subreddit => "subreddit",
+            Field::Title => "title",
+        }
+    }
+
+    // Whether the value is a name kept as typed, None when the field takes
+    // words
+    fn is_name(&self, value: &str) -> Option<bool> {
+        let separators: &[char] = match self {
+            Field::Author | Field::Subreddit => &['_', '-'],
+            Field::Site => &['.', '-'],
+            Field::Title => return None,
+        };
+        let is_valid = |x: char| x.is_ascii_alphanumeric() || separators.contains(&x);
+        Some(!value.is_empty() && value.chars().all(is_valid))
+    }
+}
+
+// Words are lowercase letters and digits only, and names, like
+// "Earth_Porn" or "i.redd.it", are kept as typed but only with the letters,
+// digits and separators of their field, so nothing the user types can change
+// the meaning of the rest of the query
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    Word(String),
+    Phrase(Vec<String>),
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match {
+    pub field: Option<Field>,
+    pub term: Term,
+}
+
+// Matches when any of the alternatives does, or when none does if negated
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clause {
+    pub negated: bool,
+    pub alternatives: Vec<Match>,
+}
+
+// Every clause must match
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub clauses: Vec<Clause>,
+}
+
+fn get_term(words: Vec<String>) -> Option<Term> {
+    match words.len() {
+        0 => None,
+        1 => words.into_iter().next().map(Term::Word),
+        _ => Some(Term::Phrase(words)),
+    }
+}
+
+// Text up to the closing quote, or the end of the query when it is missing
+fn read_phrase(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    chars.next();
+    let mut phrase = String::new();
+    for c in chars {
+        if c == '"' {
+            break;
+        }
+        phrase.push(c);
+    }
+    phrase
+}
+
+fn read_token(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut token = String::new();
+    while let Some(c) = chars.peek() {
+        if c.is_whitespace() || *c == '"' {
+            break;
+        }
+        token.push(*c);
+        chars.next();
+    }
+    token
+}
+
+// Words, "quoted phrases", a OR b, NOT a or -a, and author:, site:,
+// subreddit: and title: qualifiers. Anything else, like parentheses or
+// unknown operators, is ignored, and invalid names are searched as words.
+pub fn parse_query(s: &str) -> Query {
+    let mut clauses: Vec<Clause> = vec![];
+    let mut negated = false;
+    let mut or = false;
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let (field, text) = match chars.peek() {
+            None => break,
+            Some('"') => (None, read_phrase(&mut chars)),
+            Some('-') => {
+                chars.next();
+                negated = true;
+                continue;
+            }
+            Some(_) => {
+                let token = read_token(&mut chars);
+                if token == "OR" {
+                    or = true;
+                    continue;
+                }
+                if token == "NOT" {
+                    negated = true;
+                    continue;
+                }
+                // parentheses are ignored, but they can wrap a qualifier
+                let qualifier = token.trim_matches(|x| x == '(' || x == ')');
+                let qualifier = qualifier.find(':').and_then(|i| {
+                    let value = qualifier[i + 1..].to_owned();
+                    Field::parse(&qualifier[..i]).map(|field| (field, value))
+                });
+                let qualifier = match qualifier {
+                    // title:"red car"
+                    Some((field, value)) if value.is_empty() && chars.peek() == Some(&'"') => {
+                        Some((field, read_phrase(&mut chars)))
+                    }
+                    x => x,
+                };
+                match qualifier {
+                    Some((field, value)) => match field.is_name(&value) {
+                        Some(true) => {
+                            let alternative = Match {
+                                field: Some(field),
+                                term: Term::Name(value),
+                            };
+                            add_alternative(&mut clauses, alternative, negated, or);
+                            negated = false;
+                            or = false;
+                            continue;
+                        }
+                        Some(false) => (None, format!("{} {}", field.name(), value)),
+                        None => (Some(field), value),
+                    },
+                    None => (None, token),
+                }
+            }
+        };
+        let term = match get_term(get_words(&text)) {
+            Some(term) => term,
+            None => continue,
+        };
+        add_alternative(&mut clauses, Match { field, term }, negated, or);
+        negated = false;
+        or = false;
+    }
+    Query { clauses }
+}
+
+// An alternative of the last clause after OR, a new clause otherwise
+fn add_alternative(clauses: &mut Vec<Clause>, alternative: Match, negated: bool, or: bool) {
+    match clauses.last_mut() {
+        Some(last) if or && !negated => last.alternatives.push(alternative),
+        _ => clauses.push(Clause {
+            negated,
+            alternatives: vec![alternative],
+        }),
+    }
+}
+
+fn format_match(x: &Match) -> String {
+    let term = match &x.term {
+        Term::Word(word) | Term::Name(word) => word.clone(),
+        Term::Phrase(words) => format!("\"{}\"", words.join(" ")),
+    };
+    match x.field {
+        None => term,
+        Some(field) => format!("{}:{}", field.name(), term),
+    }
+}
+
+fn contains_term(words: &[String], term: &Term) -> bool {
+    match term {
+        Term::Word(word) => words.iter().any(|x| x.contains(word.as_str())),
+        Term::Phrase(phrase) => words
+            .windows(phrase.len())
+            .any(|x| x.iter().zip(phrase).all(|(a, b)| a.contains(b.as_str()))),
+        // the words of the name, as documents have them
+        Term::Name(name) => get_term(get_words(name)).map_or(false, |x| contains_term(words, &x)),
+    }
+}
+
+impl Query {
+    pub fn is_empty(&self) -> bool {
+        self.clauses.is_empty()
+    }
+
+    // Narrowed to a name of the field, like subreddit:EarthPorn, unchanged
+    // when the name is invalid
+    pub fn and_name(mut self, field: Field, name: &str) -> Query {
+        if field.is_name(name) == Some(true) {
+            self.clauses.push(Clause {
+                negated: false,
+                alternatives: vec![Match {
+                    field: Some(field),
+                    term: Term::Name(name.to_owned()),
+                }],
+            });
+        }
+        self
+    }
+
+    // In reddit search syntax
+    pub fn to_reddit(&self) -> String {
+        let clauses: Vec<String> = self
+            .clauses
+            .iter()
+            .map(|clause| {
+                let alternatives: Vec<String> =
+                    clause.alternatives.iter().map(format_match).collect();
+                let text = if alternatives.len() == 1 {
+                    alternatives.join("")
+                } else {
+                    format!("({})", alternatives.join(" OR "))
+                };
+                if clause.negated {
+                    format!("NOT {}", text)
+                } else {
+                    text
+                }
+            })
+            .collect();
+        clauses.join(" ")
+    }
+
+    // Words that make a result relevant, the ones of negated clauses do not
+    pub fn words(&self) -> Vec<String> {
+        let mut words = vec![];
+        for clause in self.clauses.iter().filter(|x| !x.negated) {
+            for alternative in clause.alternatives.iter() {
+                match &alternative.term {
+                    Term::Word(word) => words.push(word.clone()),
+                    Term::Phrase(phrase) => words.extend(phrase.iter().cloned()),
+                    Term::Name(name) => words.extend(get_words(name)),
+                }
+            }
+        }
+        words
+    }
+
+    // Whether a document with these words matches. Qualifiers are ignored,
+    // words can be in any field, and like matches_query they can be part of a
+    // longer word.
+    pub fn matches(&self, words: &[String]) -> bool {
+        self.clauses.iter().all(|clause| {
+            let found = clause
+                .alternatives
+                .iter()
+                .any(|x| contains_term(words, &x.term));
+            found != clause.negated
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reddit(s: &str) -> String {
+        parse_query(s).to_reddit()
+    }
+
+    #[test]
+    fn queries_keep_phrases_operators_and_qualifiers() {
+        assert_eq!(reddit("red car"), "red car");
+        assert_eq!(reddit("Café  au lait"), "café au lait");
+        assert_eq!(reddit("\"red car\" sunset"), "\"red car\" sunset");
+        assert_eq!(
+            reddit("ferrari OR lamborghini red"),
+            "(ferrari OR lamborghini) red"
+        );
+        assert_eq!(reddit("red or blue"), "red or blue");
+        assert_eq!(reddit("ferrari -red NOT blue"), "ferrari NOT red NOT blue");
+        assert_eq!(
+            reddit("subreddit:EarthPorn title:\"Blue sea\" author:some_user"),
+            "subreddit:EarthPorn title:\"blue sea\" author:some_user"
+        );
+        assert_eq!(
+            reddit("site:i.redd.it (SUBREDDIT:Earth_Porn OR subreddit:pics)"),
+            "site:i.redd.it (subreddit:Earth_Porn OR subreddit:pics)"
+        );
+        assert_eq!(
+            reddit("subreddit:cars OR subreddit:pics"),
+            "(subreddit:cars OR subreddit:pics)"
+        );
+        assert_eq!(
+            reddit("site:evil.com (red) {car} \"open"),
+            "site:evil.com red car open"
+        );
+        assert_eq!(
+            reddit("subreddit:a/b site:evil.com/x author:\"x y\""),
+            "\"subreddit a b\" \"site evil com x\" \"author x y\""
+        );
+        assert_eq!(reddit("OR NOT - \"\" ()"), "");
+    }
+
+    #[test]
+    fn matches_follows_the_operators() {
+        let words = get_words("Red Ferrari at the beach, r/cars");
+        let matches = |query: &str| parse_query(query).matches(&words);
+        assert!(matches(""));
+        assert!(matches("ferrari"));
+        assert!(matches("\"red ferrari\""));
+        assert!(!matches("\"ferrari red\""));
+        assert!(matches("blue OR red"));
+        assert!(!matches("ferrari -red"));
+        assert!(matches("subreddit:cars NOT title:lamborghini"));
+        assert!(matches("subreddit:Red_Ferrari"));
+        assert!(!matches("author:some_user"));
+    }
+
+    #[test]
+    fn names_narrow_the_whole_query() {
+        let narrowed = parse_query("red OR blue")
+            .and_name(Field::Subreddit, "EarthPorn")
+            .and_name(Field::Site, "i.redd.it")
+            .and_name(Field::Author, "a b");
+        assert_eq!(
+            narrowed.to_reddit(),
+            "(red OR blue) subreddit:EarthPorn site:i.redd.it"
+        );
+    }
+
+    // Rendered queries mean the same when parsed again
+    #[quickcheck]
+    fn to_reddit_is_parsed_back(s: String) -> bool {
+        let query = parse_query(&s);
+        parse_query(&query.to_reddit()) == query
+    }
+
+    // Names reach reddit as typed, whatever letters they mix
+    #[quickcheck]
+    fn qualifier_names_are_kept_verbatim(subreddit: String, author: String, site: String) -> bool {
+        let keep = |s: String, extra: &[char]| {
+            let s: String = s
+                .chars()
+                .filter(|x| x.is_ascii_alphanumeric() || extra.contains(x))
+                .collect();
+            format!("a{}", s)
+        };
+        let subreddit = keep(subreddit, &['_', '-']);
+        let author = keep(author, &['_', '-']);
+        let site = keep(site, &['.', '-']);
+        let s = format!("subreddit:{} author:{} site:{}", subreddit, author, site);
+        let query = parse_query(&s);
+        query.to_reddit() == s && parse_query(&query.to_reddit()) == query
+    }
+}
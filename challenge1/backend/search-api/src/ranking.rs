@@ -0,0 +1,151 @@
+use crate::query::parse_query;
+use crate::text::TextIndex;
+
+// Palette and layout distances between unrelated images are around 100
+const COLOR_DISTANCE_SCALE: f32 = 100.0;
+
+// How much the color distance and the text relevance count when ordering
+// results. Irrelevant text costs as much as a color distance of
+// 100 * text / color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RankingWeights {
+    pub color: f32,
+    pub text: f32,
+}
+
+impl Default for RankingWeights {
+    fn default() -> RankingWeights {
+        RankingWeights {
+            color: 1.0,
+            text: 0.5,
+        }
+    }
+}
+
+impl RankingWeights {
+    // Negative or infinite weights would reward the wrong results
+    pub fn is_valid(&self) -> bool {
+        [self.color, self.text]
+            .iter()
+            .all(|x| x.is_finite() && *x >= 0.0)
+    }
+
+    // Lower is better. Relevance is relative to the most relevant result, so
+    // weights mean the same whatever the query.
+    pub fn cost(&self, distance: f32, relevance: f32, max_relevance: f32) -> f32 {
+        let relevance = if max_relevance > 0.0 {
+            relevance / max_relevance
+        } else {
+            0.0
+        };
+        self.color * distance / COLOR_DISTANCE_SCALE + self.text * (1.0 - relevance)
+    }
+
+    // Sorts items by their cost, relevance is measured among the items.
+    // Items without a distance, like images that cannot be analyzed, go last.
+    pub fn rank<T>(
+        &self,
+        query: &str,
+        items: Vec<T>,
+        get: impl Fn(&T) -> (Option<f32>, Vec<String>),
+    ) -> Vec<T> {
+        let mut text = TextIndex::new();
+        let distances: Vec<Option<f32>> = items
+            .iter()
+            .map(|x| {
+                let (distance, words) = get(x);
+                text.add(&words);
+                distance
+            })
+            .collect();
+        let relevance = text.scores(&parse_query(query).words());
+        let max_relevance = relevance.values().cloned().fold(0.0, f32::max);
+
+        let mut ranked: Vec<(Option<f32>, T)> = items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| {
+                let relevance = relevance.get(&i).cloned().unwrap_or(0.0);
+                let cost = distances[i].map(|x| self.cost(x, relevance, max_relevance));
+                (cost, item)
+            })
+            .collect();
+        ranked.sort_by(|a, b| match (a.0, b.0) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+        ranked.into_iter().map(|x| x.1).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::text::get_words;
+
+    fn rank(
+        weights: RankingWeights,
+        query: &str,
+        items: &[(Option<f32>, &'static str)],
+    ) -> Vec<&'static str> {
+        weights
+            .rank(query, items.to_vec(), |x| (x.0, get_words(x.1)))
+            .into_iter()
+            .map(|x| x.1)
+            .collect()
+    }
+
+    #[test]
+    fn weights_trade_color_for_text() {
+        let items = [
+            (Some(10.0), "Red sunset"),
+            (Some(30.0), "Red Ferrari"),
+            (None, "Ferrari"),
+            (Some(20.0), "Ferrari at the track, with the red sunset"),
+        ];
+        let color = RankingWeights {
+            color: 1.0,
+            text: 0.0,
+        };
+        assert_eq!(
+            rank(color, "ferrari", &items),
+            vec![
+                "Red sunset",
+                "Ferrari at the track, with the red sunset",
+                "Red Ferrari",
+                "Ferrari"
+            ]
+        );
+        let text = RankingWeights {
+            color: 0.01,
+            text: 1.0,
+        };
+        assert_eq!(
+            rank(text, "ferrari", &items),
+            vec![
+                "Red Ferrari",
+                "Ferrari at the track, with the red sunset",
+                "Red sunset",
+                "Ferrari"
+            ]
+        );
+    }
+
+    #[quickcheck]
+    fn without_text_weight_results_follow_the_distance(distances: Vec<u8>, query: String) -> bool {
+        let weights = RankingWeights {
+            color: 1.0,
+            text: 0.0,
+        };
+        let items: Vec<(Option<f32>, String)> = distances
+            .iter()
+            .map(|x| (Some(*x as f32), x.to_string()))
+            .collect();
+        let ranked: Vec<f32> = weights
+            .rank(&query, items, |x| (x.0, get_words(&x.1)))
+            .iter()
+            .filter_map(|x| x.0)
+            .collect();
+        ranked.windows(2).all(|x| x[0] <= x[1])
+    }
+}
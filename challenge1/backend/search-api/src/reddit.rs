@@ -1,27 +1,56 @@
-use async_channel::{Receiver, Sender};
-use isahc::prelude::*;
-use palette::Lab;
-use serde::{Deserialize, Serialize};
-use std::cmp::Reverse;
-use std::collections::BinaryHeap;
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
-use crate::actors::dominant_color::DominantColorDistanceMessage;
-use crate::actors::dominant_color_cache::DominantColorCacheMessage;
-use crate::colors::lab_distance;
-use crate::loggable::Loggable;
-use crate::ord::OrdFirst;
+use crate::filter::CandidateFilter;
+use crate::formats::has_image_extension;
+use crate::http::OnFetchEvent;
+use crate::image_source::{self, Candidate, ImageSource, Listing, Page, Sort};
+use crate::query::{parse_query, Field};
+use crate::reddit_api::RedditApi;
+use crate::url_encoding::{is_unreserved, percent_encode};
 
-#[derive(Debug, Serialize)]
-struct SearchResult {
-    images: Vec<RedditResultDataChildrenData>,
+const REDDIT_SEARCH_LIMIT: u32 = 1000;
+
+// Archived dumps have some numbers as strings, and old posts without them
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::Number(x)) => x.as_f64(),
+        Some(serde_json::Value::String(x)) => x.parse().ok(),
+        _ => None,
+    })
 }
 
+// A submission, as listings and archived dumps have it
 #[derive(Serialize, Deserialize, Debug)]
-struct RedditResultDataChildrenData {
-    id: String,
-    url: String,
-    num_comments: u64,
+pub struct RedditResultDataChildrenData {
+    pub id: String,
+    pub url: String,
+    #[serde(default)]
+    pub num_comments: u64,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub subreddit: String,
+    // nsfw. Missing in old dumps, so unknown rather than false, and the same
+    // for score and domain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub over_18: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<i64>,
+    // host of the link, "self.php" for text posts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    // seconds since the epoch
+    #[serde(
+        default,
+        deserialize_with = "deserialize_timestamp",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_utc: Option<f64>,
 }
 
 impl Clone for RedditResultDataChildrenData {
@@ -30,19 +59,26 @@ impl Clone for RedditResultDataChildrenData {
             id: self.id.clone(),
             url: self.url.clone(),
             num_comments: self.num_comments,
+            title: self.title.clone(),
+            subreddit: self.subreddit.clone(),
+            over_18: self.over_18,
+            score: self.score,
+            domain: self.domain.clone(),
+            created_utc: self.created_utc,
         }
     }
 }
 
 #[derive(Deserialize, Debug)]
-struct RedditResultDataChildren {
-    kind: String,
-    data: RedditResultDataChildrenData,
+pub struct RedditResultDataChildren {
+    pub kind: String,
+    pub data: RedditResultDataChildrenData,
 }
 
 #[derive(Deserialize, Debug)]
 struct RedditResultData {
-    after: String,
+    // null on the last page
+    after: Option<String>,
     children: Vec<RedditResultDataChildren>,
 }
 
@@ -52,7 +88,17 @@ struct RedditResult {
     data: RedditResultData,
 }
 
-fn is_image(url: &str) -> Option<String> {
+// Hosts serving the image itself at urls without an extension
+const DIRECT_IMAGE_HOSTS: &[&str] = &["i.redd.it", "preview.redd.it"];
+
+// Url of the image of a post, None when it links to something else. The
+// extension is only a hint, the downloaded bytes decide the format, so
+// image hosts are kept without one.
+pub fn is_image(url: &str) -> Option<String> {
+    let path = url.split(|x| x == '?' || x == '#').next().unwrap_or(url);
+    if has_image_extension(path) || DIRECT_IMAGE_HOSTS.iter().any(|x| url.contains(x)) {
+        return Some(url.to_owned());
+    }
     if url.contains("500px.com")
         || url.contains("abload.de")
         || url.contains("deviantart.com")
@@ -84,105 +130,23 @@ fn is_image(url: &str) -> Option<String> {
     {
         if url.ends_with(".gifv") {
             return Some(url.replace(".gifv", ".gif"));
-        } else if !url.ends_with(".png") && !url.ends_with(".jpg") && !url.ends_with(".gif") {
+        } else if url.contains('?') {
+            // the query may pick the image, it cannot get an extension
+            return Some(url.to_owned());
+        } else if !has_image_extension(url) {
             return Some(format!("{}.png", url));
         } else {
             return Some(url.to_owned());
         }
     } else if url.ends_with(".gifv") {
         return Some(url.replace(".gifv", ".gif"));
-    } else if url.ends_with(".png") || url.ends_with(".jpg") || url.ends_with(".gif") {
+    } else if has_image_extension(path) {
         return Some(url.to_owned());
     } else {
         None
     }
 }
 
-async fn get_distance(
-    cache_actor: &Sender<DominantColorCacheMessage>,
-    dist_actor: &Sender<DominantColorDistanceMessage>,
-    url: &String,
-    desired_color: Lab,
-) -> Result<u32, ErrorCode> {
-    log::trace!("get_distance");
-    let (w, s) = oneshot::channel();
-    cache_actor
-        .send(DominantColorCacheMessage::Read(url.clone(), w))
-        .await
-        .or(Err(ErrorCode::CannotSendToCache))?;
-    if let Some(lab) = s.await.or(Err(ErrorCode::CannotWaitCache))? {
-        log::trace!("get_distance: 1.1");
-        return Ok(lab_distance(&lab, &desired_color) as u32);
-    }
-    log::trace!("get_distance: 2");
-    let (w, s) = oneshot::channel();
-    if let Err(_) = dist_actor
-        .send(DominantColorDistanceMessage(url.clone(), desired_color, w))
-        .await
-    {
-        return Ok(u32::MAX);
-    }
-    log::trace!("get_distance: 3");
-    match s.await {
-        Err(_) => Ok(u32::MAX),
-        Ok(None) => Ok(u32::MAX),
-        Ok(Some((dominant_color, distance))) => {
-            log::trace!("get_distance: 4");
-            cache_actor
-                .send(DominantColorCacheMessage::Write(
-                    url.clone(),
-                    dominant_color,
-                ))
-                .await
-                .or(Err(ErrorCode::Error))?;
-            log::trace!("get_distance: 5");
-            Ok(distance)
-        }
-    }
-}
-
-async fn run_and_log(reddit: impl std::future::Future<Output = Result<SearchResult, ErrorCode>>) {
-    let _ = reddit.await.log_if_error();
-}
-
-pub fn get_reddit_with_progress(
-    q: String,
-    lab: Lab,
-    cache_actor: Sender<DominantColorCacheMessage>,
-    dist_actor: Sender<DominantColorDistanceMessage>,
-) -> Receiver<String> {
-    let (progress, r) = async_channel::unbounded::<String>();
-    let reddit = get_reddit(q, lab, cache_actor, dist_actor, progress);
-    tokio::spawn(run_and_log(reddit));
-    r
-}
-
-async fn send_progress(
-    progress: &Sender<String>,
-    v: f32,
-    msg: Option<&str>,
-) -> Result<(), ErrorCode> {
-    // log::trace!("start {} {:?}", v, msg);
-    let str = match msg {
-        None => format!("{{\"v\":{}}}", v),
-        Some(msg) => format!("{{\"v\":{},\"msg\":\"{}\"}}", v, msg),
-    };
-    progress
-        .send(str)
-        .await
-        .or(Err(ErrorCode::CannotSendProgress))?;
-    Ok(())
-}
-
-async fn send_progress_result<T: Serialize>(
-    progress: &Sender<String>,
-    obj: T,
-) -> Result<T, ErrorCode> {
-    let r_json = serde_json::to_string(&obj).or(Err(ErrorCode::Error))?;
-    progress.send(r_json).await.or(Err(ErrorCode::Error))?;
-    Ok(obj)
-}
-
 #[derive(Debug, Error)]
 pub enum ErrorCode {
     #[error("generic error")]
@@ -195,116 +159,279 @@ pub enum ErrorCode {
     InvalidSend,
     #[error("cannot parse response json")]
     InvalidResponse,
-    #[error("cannot send progress")]
-    CannotSendProgress,
-    #[error("cannot send to cache")]
-    CannotSendToCache,
-    #[error("cannot wait cache")]
-    CannotWaitCache,
 }
 
-async fn call_reddit_search_api(url: &str) -> Result<RedditResult, ErrorCode> {
-    let mut response = Request::get(url)
-        .header("User-Agent", "linux:isahc:searchapi")
-        .body(())
-        .or(Err(ErrorCode::InvalidBody))?
-        .send_async()
+async fn call_reddit_search_api(
+    reddit: &RedditApi,
+    url: &str,
+    on_event: OnFetchEvent,
+) -> Result<RedditResult, ErrorCode> {
+    let fetched = reddit
+        .fetch_reporting(url, on_event)
         .await
         .or(Err(ErrorCode::InvalidSend))?;
-    let reddit = response
-        .json::<RedditResult>()
+    let reddit = serde_json::from_slice::<RedditResult>(&fetched.body)
         .or(Err(ErrorCode::InvalidResponse))?;
     Ok(reddit)
 }
 
-fn get_reddit_search_url(query: &str, limit: u32, after: Option<String>) -> Option<String> {
-    let query = query.replace(|c: char| !c.is_ascii() || !c.is_alphanumeric(), "");
-    if query.len() == 0 {
+// Only posts linking to image hosts
+const REDDIT_IMAGE_SITES: &[&str] = &[
+    "500px.com",
+    "abload.de",
+    "deviantart.com",
+    "deviantart.net",
+    "fav.me",
+    "fbcdn.net",
+    "flickr.com",
+    "forgifs.com",
+    "giphy.com",
+    "gfycat.com",
+    "gifsoup.com",
+    "gyazo.com",
+    "i.redd.it",
+    "imageshack.us",
+    "imgclean.com",
+    "imgur.com",
+    "instagr.am",
+    "instagram.com",
+    "mediacru.sh",
+    "media.tumblr.com",
+    "min.us",
+    "minus.com",
+    "myimghost.com",
+    "photobucket.com",
+    "picsarus.com",
+    "puu.sh",
+    "staticflickr.com",
+    "tinypic.com",
+    "twitpic.com",
+];
+
+// None when the query has nothing to search. The query is parsed and written
+// back, so phrases, operators and qualifiers reach reddit while anything else
+// cannot change the site filter.
+fn get_reddit_search_url(
+    base_url: &str,
+    query: &str,
+    listing: &Listing,
+    limit: u32,
+    after: Option<String>,
+) -> Option<String> {
+    let query = parse_query(query);
+    if query.is_empty() {
         return None;
     }
-    let r = match after {
-        None => format!("https://www.reddit.com/r/php/search.json?q={}%20site:(500px.com%20OR%20abload.de%20OR%20deviantart.com%20OR%20deviantart.net%20OR%20fav.me%20OR%20fbcdn.net%20OR%20flickr.com%20OR%20forgifs.com%20OR%20giphy.com%20OR%20gfycat.com%20OR%20gifsoup.com%20OR%20gyazo.com%20OR%20imageshack.us%20OR%20imgclean.com%20OR%20imgur.com%20OR%20instagr.am%20OR%20instagram.com%20OR%20mediacru.sh%20OR%20media.tumblr.com%20OR%20min.us%20OR%20minus.com%20OR%20myimghost.com%20OR%20photobucket.com%20OR%20picsarus.com%20OR%20puu.sh%20OR%20staticflickr.com%20OR%20tinypic.com%20OR%20twitpic.com)&limit={}&sort=comments&restrict_sr=0", 
-            query, limit),
-        Some(after) => format!("https://www.reddit.com/r/php/search.json?q={}%20site:(500px.com%20OR%20abload.de%20OR%20deviantart.com%20OR%20deviantart.net%20OR%20fav.me%20OR%20fbcdn.net%20OR%20flickr.com%20OR%20forgifs.com%20OR%20giphy.com%20OR%20gfycat.com%20OR%20gifsoup.com%20OR%20gyazo.com%20OR%20imageshack.us%20OR%20imgclean.com%20OR%20imgur.com%20OR%20instagr.am%20OR%20instagram.com%20OR%20mediacru.sh%20OR%20media.tumblr.com%20OR%20min.us%20OR%20minus.com%20OR%20myimghost.com%20OR%20photobucket.com%20OR%20picsarus.com%20OR%20puu.sh%20OR%20staticflickr.com%20OR%20tinypic.com%20OR%20twitpic.com)&limit={}&sort=comments&restrict_sr=0&after={}", 
-            query, &after, limit)
+    let q = format!(
+        "({}) site:({})",
+        query.to_reddit(),
+        REDDIT_IMAGE_SITES.join(" OR ")
+    );
+    let mut r = format!(
+        "{}/r/php/search.json?q={}&limit={}&sort={}&t={}&restrict_sr=0",
+        base_url,
+        percent_encode(&q, is_unreserved),
+        limit,
+        listing.sort.as_str(),
+        listing.time.as_str()
+    );
+    if let Some(after) = after {
+        r.push_str(&format!("&after={}", percent_encode(&after, is_unreserved)));
+    }
+    Some(r)
+}
+
+// The subreddit and domain filters as qualifiers, reddit site: also matches
+// subdomains
+fn get_narrowed_query(query: &str, filter: &CandidateFilter) -> String {
+    let mut query = parse_query(query);
+    if let Some(subreddit) = &filter.subreddit {
+        query = query.and_name(Field::Subreddit, subreddit);
+    }
+    if let Some(domain) = &filter.domain {
+        query = query.and_name(Field::Site, domain);
+    }
+    query.to_reddit()
+}
+
+fn is_subreddit_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+// None for an invalid name or a sort subreddits are not listed by
+fn get_subreddit_listing_url(
+    base_url: &str,
+    subreddit: &str,
+    listing: &Listing,
+    limit: u32,
+    after: Option<String>,
+) -> Option<String> {
+    if !is_subreddit_name(subreddit) {
+        return None;
+    }
+    let sort = match listing.sort {
+        Sort::Hot | Sort::New | Sort::Top => listing.sort.as_str(),
+        Sort::Relevance | Sort::Comments => return None,
     };
+    let mut r = format!(
+        "{}/r/{}/{}.json?limit={}&t={}",
+        base_url,
+        subreddit,
+        sort,
+        limit,
+        listing.time.as_str()
+    );
+    if let Some(after) = after {
+        r.push_str(&format!("&after={}", percent_encode(&after, is_unreserved)));
+    }
     Some(r)
 }
 
+// Posts with an image, the rest of the post fields become the candidate
+// metadata
+pub fn get_candidate(data: RedditResultDataChildrenData) -> Option<Candidate> {
+    let url = is_image(&data.url)?;
+    let mut metadata = match serde_json::to_value(&data) {
+        Ok(serde_json::Value::Object(metadata)) => metadata,
+        _ => return None,
+    };
+    metadata.remove("id");
+    metadata.remove("url");
+    Some(Candidate {
+        id: data.id,
+        url,
+        metadata,
+    })
+}
+
 //https://www.reddit.com/r/php/search.json?q=oop&limit=5&sort=hot&restrict_sr=0
-async fn get_reddit(
-    q: String,
-    lab: Lab,
-    cache_actor: Sender<DominantColorCacheMessage>,
-    dist_actor: Sender<DominantColorDistanceMessage>,
-    progress: Sender<String>,
-) -> Result<SearchResult, ErrorCode> {
-    send_progress(&progress, 0.0, None).await?;
-
-    let return_qtd = 3;
-    let total = return_qtd * 100;
-    let mut after: Option<String> = None;
-
-    let mut candidates = BinaryHeap::new();
-
-    let reddit_search_limit = 1000;
-    let mut currenti = 0.0f32;
-    loop {
-        let url =
-            get_reddit_search_url(&q, reddit_search_limit, after).ok_or(ErrorCode::InvalidUrl)?;
-        let reddit = call_reddit_search_api(&url).await?;
-        after = Some(reddit.data.after);
-        for item in reddit.data.children.iter() {
-            currenti += 1.0;
-            if let Some(url) = is_image(&item.data.url) {
-                send_progress(&progress, currenti / total as f32, Some(&url)).await?;
-                log::info!("start 1");
-                let distance = get_distance(&cache_actor, &dist_actor, &url, lab).await?;
-                log::info!("start 2");
-                let data = RedditResultDataChildrenData {
-                    url: url.clone(),
-                    ..item.data.clone()
-                };
-                log::info!("start 3");
-                candidates.push(Reverse(OrdFirst(distance, data)));
-            }
+pub struct RedditSource {
+    reddit: RedditApi,
+}
 
-            if candidates.len() == total {
-                break;
-            }
-        }
+impl RedditSource {
+    pub fn new(reddit: RedditApi) -> RedditSource {
+        RedditSource { reddit }
+    }
 
-        if candidates.len() == total {
-            break;
-        }
+    // Searches and subreddits answer the same listing of posts
+    async fn list(
+        &self,
+        url: &str,
+        on_event: OnFetchEvent,
+    ) -> Result<Page, image_source::ErrorCode> {
+        let result = call_reddit_search_api(&self.reddit, url, on_event)
+            .await
+            .map_err(|x| image_source::ErrorCode::CannotList(x.to_string()))?;
+        let candidates = result
+            .data
+            .children
+            .into_iter()
+            .filter_map(|x| get_candidate(x.data))
+            .collect();
+        Ok(Page {
+            candidates,
+            next: result.data.after,
+        })
+    }
+}
+
+#[async_trait]
+impl ImageSource for RedditSource {
+    fn name(&self) -> &str {
+        "reddit"
+    }
+
+    fn narrow_query(&self, query: &str, filter: &CandidateFilter) -> String {
+        get_narrowed_query(query, filter)
     }
 
-    let mut images: Vec<RedditResultDataChildrenData> = Vec::with_capacity(return_qtd);
-    for Reverse(OrdFirst(_, item)) in candidates.iter().take(return_qtd) {
-        images.push(item.clone());
+    async fn next_page(
+        &self,
+        query: &str,
+        listing: &Listing,
+        cursor: Option<String>,
+        on_event: OnFetchEvent,
+    ) -> Result<Page, image_source::ErrorCode> {
+        let url = get_reddit_search_url(
+            self.reddit.base_url(),
+            query,
+            listing,
+            REDDIT_SEARCH_LIMIT,
+            cursor,
+        )
+        .ok_or(image_source::ErrorCode::InvalidQuery)?;
+        self.list(&url, on_event).await
     }
 
-    send_progress(&progress, 1.0, None).await?;
-    let result = send_progress_result(&progress, SearchResult { images }).await?;
-    Ok(result)
+    async fn next_subreddit_page(
+        &self,
+        subreddit: &str,
+        listing: &Listing,
+        cursor: Option<String>,
+        on_event: OnFetchEvent,
+    ) -> Result<Page, image_source::ErrorCode> {
+        let url = get_subreddit_listing_url(
+            self.reddit.base_url(),
+            subreddit,
+            listing,
+            REDDIT_SEARCH_LIMIT,
+            cursor,
+        )
+        .ok_or(image_source::ErrorCode::InvalidQuery)?;
+        self.list(&url, on_event).await
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::image_source::{Sort, TimeRange};
     #[quickcheck]
     fn anything_jpg_png_gif_or_gifv_is_image(path: String) -> bool {
+        let path = path.replace(|x| x == '?' || x == '#', "");
         test_ext(&path, ".jpg")
             && test_ext(&path, ".png")
             && test_ext(&path, ".gif")
             && test_ext_change(&path, ".gifv", ".gif")
     }
 
+    #[quickcheck]
+    fn modern_formats_are_images(path: String) -> bool {
+        let path = path.replace(|x| x == '?' || x == '#', "");
+        test_ext(&path, ".jpeg")
+            && test_ext(&path, ".webp")
+            && test_ext(&path, ".bmp")
+            && test_ext(&path, ".tiff")
+            && test_ext(&path, ".JPG")
+    }
+
+    #[quickcheck]
+    fn dont_append_png_for_known_domains_with_image_extension(path: String) -> bool {
+        test_ext_change("fbcdn.net/", &format!("{}.jpeg", path), ".jpeg")
+            && test_ext_change("fbcdn.net/", &format!("{}.webp", path), ".webp")
+    }
+
     #[quickcheck]
     fn append_png_for_known_domains(path: String) -> bool {
+        let path = path.replace(|x| x == '?' || x == '#', "");
         test_ext_change("fbcdn.net/", &path, ".png")
     }
 
+    #[test]
+    fn urls_without_extension_are_kept_for_image_hosts() {
+        for url in &[
+            "https://i.redd.it/abc",
+            "https://i.imgur.com/abc.jpg?1",
+            "https://i.imgur.com/abc?format=webp",
+            "https://example.com/abc.webp?width=640#top",
+        ] {
+            assert_eq!(is_image(url).as_deref(), Some(*url));
+        }
+        assert_eq!(is_image("https://example.com/article"), None);
+        assert_eq!(is_image("https://example.com/article?a.png"), None);
+    }
+
     #[quickcheck]
     fn dont_append_png_for_known_domains_that_ends_with_png(path: String) -> bool {
         !is_image(&format!("fbcdn.net/{}.png", path))
@@ -312,11 +439,64 @@ mod test {
             .ends_with(".png.png")
     }
 
+    #[test]
+    fn candidates_keep_post_fields_as_metadata() {
+        let data = RedditResultDataChildrenData {
+            id: "abc".to_owned(),
+            url: "https://i.imgur.com/abc.gifv".to_owned(),
+            num_comments: 42,
+            title: "Red car".to_owned(),
+            subreddit: "cars".to_owned(),
+            over_18: Some(false),
+            score: Some(7),
+            domain: Some("i.imgur.com".to_owned()),
+            created_utc: None,
+        };
+        let candidate = get_candidate(data.clone()).unwrap();
+        assert_eq!(candidate.id, "abc");
+        assert_eq!(candidate.url, "https://i.imgur.com/abc.gif");
+        assert_eq!(
+            serde_json::Value::Object(candidate.metadata),
+            serde_json::json!({
+                "num_comments": 42,
+                "title": "Red car",
+                "subreddit": "cars",
+                "over_18": false,
+                "score": 7,
+                "domain": "i.imgur.com"
+            })
+        );
+        let data = RedditResultDataChildrenData {
+            url: "https://example.com/post".to_owned(),
+            ..data
+        };
+        assert!(get_candidate(data).is_none());
+    }
+
+    #[test]
+    fn posts_read_dump_timestamps() {
+        let post = r#"{"id":"a","url":"b","created_utc":"1262304000"}"#;
+        let post = serde_json::from_str::<RedditResultDataChildrenData>(post).unwrap();
+        assert_eq!(post.created_utc, Some(1262304000.0));
+        assert_eq!(post.num_comments, 0);
+        assert_eq!((post.over_18, post.score, post.domain), (None, None, None));
+        let post = r#"{"id":"a","url":"b","title":"t","created_utc":1262304000.5}"#;
+        let post = serde_json::from_str::<RedditResultDataChildrenData>(post).unwrap();
+        assert_eq!(post.created_utc, Some(1262304000.5));
+        assert_eq!(post.title, "t");
+    }
+
     use std::convert::TryFrom;
     use uriparse::uri::*;
     #[quickcheck]
     fn get_reddit_search_url_must_sanitize_query(query: String) -> bool {
-        match get_reddit_search_url(&query, 0, None) {
+        match get_reddit_search_url(
+            "https://www.reddit.com",
+            &query,
+            &Listing::default(),
+            0,
+            None,
+        ) {
             None => true,
             Some(url) => {
                 let url = url.as_bytes();
@@ -326,6 +506,93 @@ mod test {
         }
     }
 
+    #[test]
+    fn get_reddit_search_url_encodes_the_parsed_query() {
+        let listing = Listing::default();
+        let url =
+            |query: &str| get_reddit_search_url("https://r", query, &listing, 10, None).unwrap();
+        assert!(url("café").starts_with(
+            "https://r/r/php/search.json?q=%28caf%C3%A9%29%20site%3A%28500px.com%20OR%20"
+        ));
+        assert!(url("\"red car\" OR title:ferrari -blue")
+            .contains("q=%28%28%22red%20car%22%20OR%20title%3Aferrari%29%20NOT%20blue%29%20site"));
+        assert!(url("red&limit=1").contains("q=%28%22red%20limit%201%22%29%20site"));
+        assert!(url("subreddit:Earth_Porn site:i.redd.it")
+            .contains("q=%28subreddit%3AEarth_Porn%20site%3Ai.redd.it%29%20site"));
+        assert!(get_reddit_search_url("https://r", "() - OR", &listing, 10, None).is_none());
+        assert!(
+            get_reddit_search_url("https://r", "red", &listing, 10, Some("t3_x".to_owned()))
+                .unwrap()
+                .ends_with("&sort=comments&t=all&restrict_sr=0&after=t3_x")
+        );
+    }
+
+    #[test]
+    fn get_reddit_search_url_uses_the_listing() {
+        let listing = Listing {
+            sort: Sort::New,
+            time: TimeRange::Week,
+        };
+        let url = get_reddit_search_url("https://r", "red", &listing, 10, None).unwrap();
+        assert!(url.ends_with("&limit=10&sort=new&t=week&restrict_sr=0"));
+    }
+
+    #[test]
+    fn filters_narrow_the_search() {
+        let filter = CandidateFilter {
+            subreddit: Some("Earth_Porn".to_owned()),
+            domain: Some("i.redd.it".to_owned()),
+            ..CandidateFilter::default()
+        };
+        assert_eq!(
+            get_narrowed_query("sea OR lake", &filter),
+            "(sea OR lake) subreddit:Earth_Porn site:i.redd.it"
+        );
+        assert_eq!(
+            get_narrowed_query("sea", &CandidateFilter::default()),
+            "sea"
+        );
+        let filter = CandidateFilter {
+            subreddit: Some("a) OR (b".to_owned()),
+            ..CandidateFilter::default()
+        };
+        assert_eq!(get_narrowed_query("sea", &filter), "sea");
+    }
+
+    #[test]
+    fn subreddits_are_listed_by_sort() {
+        let listing = |sort| Listing {
+            sort,
+            time: TimeRange::Week,
+        };
+        assert_eq!(
+            get_subreddit_listing_url("https://r", "EarthPorn", &listing(Sort::Top), 10, None)
+                .as_deref(),
+            Some("https://r/r/EarthPorn/top.json?limit=10&t=week")
+        );
+        assert_eq!(
+            get_subreddit_listing_url(
+                "https://r",
+                "cars",
+                &listing(Sort::New),
+                10,
+                Some("t3_x".to_owned())
+            )
+            .as_deref(),
+            Some("https://r/r/cars/new.json?limit=10&t=week&after=t3_x")
+        );
+        assert_eq!(
+            get_subreddit_listing_url("https://r", "cars", &listing(Sort::Comments), 10, None),
+            None
+        );
+        for name in ["", "../api", "cars/top", "cars?x=1"].iter() {
+            assert_eq!(
+                get_subreddit_listing_url("https://r", name, &listing(Sort::New), 10, None),
+                None
+            );
+        }
+    }
+
     fn test_ext(path: &str, ext: &str) -> bool {
         is_image(&format!("{}{}", path, ext))
             .unwrap()
@@ -0,0 +1,219 @@
+use async_channel::Sender;
+use log::warn;
+use thiserror::Error;
+
+use crate::actors::reddit_token::{spawn_reddit_token, Credentials, RedditTokenMessage};
+use crate::http::{self, Client, FetchEvent, Fetched};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ErrorCode {
+    #[error("cannot get reddit token")]
+    NoToken,
+    #[error("{0}")]
+    CannotFetch(#[from] http::ErrorCode),
+}
+
+#[derive(Clone, Debug)]
+pub struct RedditOptions {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub token_url: String,
+    // listings with a token
+    pub oauth_url: String,
+    // anonymous listings, used without credentials
+    pub public_url: String,
+}
+
+impl Default for RedditOptions {
+    fn default() -> Self {
+        RedditOptions {
+            client_id: None,
+            client_secret: None,
+            token_url: "https://www.reddit.com/api/v1/access_token".to_owned(),
+            oauth_url: "https://oauth.reddit.com".to_owned(),
+            public_url: "https://www.reddit.com".to_owned(),
+        }
+    }
+}
+
+// Listing requests to reddit. With credentials they go to the oauth api
+// with an application only token, which has far higher limits than the
+// anonymous api.
+#[derive(Clone)]
+pub struct RedditApi {
+    client: Client,
+    base_url: String,
+    tokens: Option<Sender<RedditTokenMessage>>,
+}
+
+impl RedditApi {
+    pub fn new(client: Client, options: RedditOptions) -> RedditApi {
+        match (options.client_id, options.client_secret) {
+            (Some(client_id), Some(client_secret)) => {
+                let credentials = Credentials {
+                    client_id,
+                    client_secret,
+                    token_url: options.token_url,
+                };
+                RedditApi {
+                    tokens: Some(spawn_reddit_token(client.clone(), credentials)),
+                    client,
+                    base_url: options.oauth_url,
+                }
+            }
+            _ => {
+                warn!("no reddit credentials, using the anonymous api");
+                RedditApi {
+                    client,
+                    base_url: options.public_url,
+                    tokens: None,
+                }
+            }
+        }
+    }
+
+    // Listing urls must start with it
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    async fn get_token(tokens: &Sender<RedditTokenMessage>) -> Result<String, ErrorCode> {
+        let (w, s) = oneshot::channel();
+        tokens
+            .send(RedditTokenMessage::Get(w))
+            .await
+            .or(Err(ErrorCode::NoToken))?;
+        s.await.ok().flatten().ok_or(ErrorCode::NoToken)
+    }
+
+    // Fetches with the current token. A token reddit rejects before it
+    // expires is renewed and the fetch tried again, once.
+    pub async fn fetch_reporting(
+        &self,
+        url: &str,
+        mut on_event: impl FnMut(&FetchEvent),
+    ) -> Result<Fetched, ErrorCode> {
+        let tokens = match &self.tokens {
+            Some(tokens) => tokens,
+            None => return Ok(self.client.fetch_reporting(url, on_event).await?),
+        };
+        let mut renewed = false;
+        loop {
+            let authorization = RedditApi::get_token(tokens).await?;
+            let client = self.client.with_authorization(authorization.clone());
+            match client.fetch_reporting(url, &mut on_event).await {
+                Err(http::ErrorCode::UnexpectedStatus(401)) if !renewed => {
+                    let _ = tokens
+                        .send(RedditTokenMessage::Invalidate(authorization))
+                        .await;
+                    renewed = true;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fetch_policy::FetchPolicy;
+    use crate::http::FetchOptions;
+    use crate::test_server::{ok, serve, status, TestRequest};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const LISTING: &str = "{\"kind\":\"Listing\",\"data\":{\"after\":\"t3_b\",\"children\":[]}}";
+
+    // Fake token endpoint, numbering the tokens it gives
+    fn token(request: &TestRequest, requests: &AtomicUsize) -> String {
+        // "id:secret"
+        if request.method != "POST"
+            || request.header("authorization") != Some("Basic aWQ6c2VjcmV0")
+            || request.body != "grant_type=client_credentials"
+        {
+            return ok("{\"error\": 401}");
+        }
+        let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+        ok(&format!(
+            concat!(
+                "{{\"access_token\":\"token{}\",\"token_type\":\"bearer\",",
+                "\"expires_in\":86400,\"scope\":\"*\"}}"
+            ),
+            n
+        ))
+    }
+
+    fn listing(request: &TestRequest, accepted: &str) -> String {
+        if request.header("authorization") == Some(accepted) {
+            ok(LISTING)
+        } else {
+            status(401)
+        }
+    }
+
+    fn local_api(url: &str, client_secret: &str) -> RedditApi {
+        let policy = FetchPolicy {
+            allow_hosts: vec!["127.0.0.1".to_owned()],
+            deny_hosts: vec![],
+        };
+        let client = Client::new(FetchOptions::default(), policy).unwrap();
+        let options = RedditOptions {
+            client_id: Some("id".to_owned()),
+            client_secret: Some(client_secret.to_owned()),
+            token_url: format!("{}/api/v1/access_token", url),
+            oauth_url: url.to_owned(),
+            public_url: "http://www.reddit.invalid".to_owned(),
+        };
+        RedditApi::new(client, options)
+    }
+
+    static CACHED_TOKEN_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn tokens_are_cached() {
+        let url = serve(|request| match request.path.as_str() {
+            "/api/v1/access_token" => token(request, &CACHED_TOKEN_REQUESTS),
+            _ => listing(request, "bearer token1"),
+        })
+        .await;
+        let api = local_api(&url, "secret");
+        assert_eq!(api.base_url(), url);
+        for _ in 0..3 {
+            let listing_url = format!("{}/r/php/search.json?q=cat", api.base_url());
+            let fetched = api.fetch_reporting(&listing_url, |_| {}).await.unwrap();
+            assert_eq!(fetched.body, LISTING.as_bytes());
+        }
+        assert_eq!(CACHED_TOKEN_REQUESTS.load(Ordering::SeqCst), 1);
+    }
+
+    static RENEWED_TOKEN_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn rejected_tokens_are_renewed() {
+        let url = serve(|request| match request.path.as_str() {
+            "/api/v1/access_token" => token(request, &RENEWED_TOKEN_REQUESTS),
+            _ => listing(request, "bearer token2"),
+        })
+        .await;
+        let api = local_api(&url, "secret");
+        let listing_url = format!("{}/r/php/search.json?q=cat", api.base_url());
+        let fetched = api.fetch_reporting(&listing_url, |_| {}).await.unwrap();
+        assert_eq!(fetched.body, LISTING.as_bytes());
+        assert_eq!(RENEWED_TOKEN_REQUESTS.load(Ordering::SeqCst), 2);
+    }
+
+    static INVALID_TOKEN_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn invalid_credentials_fail_without_listing() {
+        let url = serve(|request| match request.path.as_str() {
+            "/api/v1/access_token" => token(request, &INVALID_TOKEN_REQUESTS),
+            _ => ok(LISTING),
+        })
+        .await;
+        let api = local_api(&url, "wrong");
+        let listing_url = format!("{}/r/php/search.json?q=cat", api.base_url());
+        let fetched = api.fetch_reporting(&listing_url, |_| {}).await;
+        assert_eq!(fetched.err(), Some(ErrorCode::NoToken));
+    }
+}
@@ -0,0 +1,285 @@
+use image::imageops::FilterType;
+use image::RgbaImage;
+use palette::Lab;
+use std::str::FromStr;
+
+use crate::colors::{lab_distance, rgb8_to_lab, LAYOUT_SIZE};
+
+const BORDER_TOLERANCE: f32 = 5.0;
+const TRANSPARENT_ALPHA: u8 = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Weighting {
+    Uniform,
+    Center,
+    Saliency,
+}
+
+impl FromStr for Weighting {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Weighting::Uniform),
+            "center" => Ok(Weighting::Center),
+            "saliency" => Ok(Weighting::Saliency),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn parse_filter(s: &str) -> Option<FilterType> {
+    match s {
+        "nearest" => Some(FilterType::Nearest),
+        "triangle" => Some(FilterType::Triangle),
+        "catmullrom" => Some(FilterType::CatmullRom),
+        "gaussian" => Some(FilterType::Gaussian),
+        "lanczos3" => Some(FilterType::Lanczos3),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SamplingOptions {
+    pub size: u32,
+    pub filter: FilterType,
+    pub ignore_transparent: bool,
+    pub exclude_borders: bool,
+    pub weighting: Weighting,
+    // frames of animated images that are sampled
+    pub frames: usize,
+    // decoding stops after this many frames or decoded pixel bytes
+    pub max_decoded_frames: usize,
+    pub max_decoded_bytes: u64,
+    // jpegs are decoded directly at a reduced scale
+    pub fast_jpeg: bool,
+}
+
+impl Default for SamplingOptions {
+    fn default() -> Self {
+        SamplingOptions {
+            size: 32,
+            filter: FilterType::Nearest,
+            ignore_transparent: true,
+            exclude_borders: true,
+            weighting: Weighting::Uniform,
+            frames: 8,
+            max_decoded_frames: 100,
+            max_decoded_bytes: 128 * 1024 * 1024,
+            fast_jpeg: true,
+        }
+    }
+}
+
+pub struct WeightedPixels {
+    pub pixels: Vec<Lab>,
+    pub weights: Vec<f32>,
+    // layout cell of each pixel
+    pub cells: Vec<usize>,
+}
+
+impl WeightedPixels {
+    pub fn new() -> Self {
+        WeightedPixels {
+            pixels: vec![],
+            weights: vec![],
+            cells: vec![],
+        }
+    }
+
+    pub fn append(&mut self, mut other: WeightedPixels) {
+        self.pixels.append(&mut other.pixels);
+        self.weights.append(&mut other.weights);
+        self.cells.append(&mut other.cells);
+    }
+}
+
+// Indices of up to n frames spread over the whole animation
+pub fn pick_frames(total: usize, n: usize) -> Vec<usize> {
+    if total <= n {
+        return (0..total).collect();
+    }
+    (0..n).map(|i| i * total / n).collect()
+}
+
+fn is_uniform<'a>(mut pixels: impl Iterator<Item = &'a Lab>) -> bool {
+    match pixels.next() {
+        None => true,
+        Some(first) => pixels.all(|x| lab_distance(x, first) < BORDER_TOLERANCE),
+    }
+}
+
+// Area (left, top, right, bottom) inside single color rows and columns at the
+// edges of the image, like letterbox bars or studio backgrounds. At most a
+// quarter of the image is removed from each side.
+pub fn find_content_area(pixels: &[Lab], width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let at = |x: u32, y: u32| &pixels[(y * width + x) as usize];
+    let row_is_border = |y: u32| is_uniform((0..width).map(|x| at(x, y)));
+    let column_is_border = |x: u32| is_uniform((0..height).map(|y| at(x, y)));
+
+    let mut top = 0;
+    while top < height / 4 && row_is_border(top) {
+        top += 1;
+    }
+    let mut bottom = height;
+    while height - bottom < height / 4 && row_is_border(bottom - 1) {
+        bottom -= 1;
+    }
+    let mut left = 0;
+    while left < width / 4 && column_is_border(left) {
+        left += 1;
+    }
+    let mut right = width;
+    while width - right < width / 4 && column_is_border(right - 1) {
+        right -= 1;
+    }
+    (left, top, right, bottom)
+}
+
+// Gaussian falloff from the center of the area, with x and y in -1..1
+fn center_weight(x: f32, y: f32) -> f32 {
+    (-(x * x + y * y) / 0.5).exp()
+}
+
+// Pixels far from the average color stand out from the background
+fn saliency_weights(pixels: &[Lab]) -> Vec<f32> {
+    let n = pixels.len().max(1) as f32;
+    let mean = Lab::new(
+        pixels.iter().map(|x| x.l).sum::<f32>() / n,
+        pixels.iter().map(|x| x.a).sum::<f32>() / n,
+        pixels.iter().map(|x| x.b).sum::<f32>() / n,
+    );
+    pixels
+        .iter()
+        .map(|x| 1.0 + lab_distance(x, &mean))
+        .collect()
+}
+
+pub fn sample_pixels(img: &RgbaImage, options: &SamplingOptions) -> WeightedPixels {
+    let (width, height) = img.dimensions();
+    let all_pixels: Vec<Lab> = img
+        .pixels()
+        .map(|x| rgb8_to_lab(x.0[0], x.0[1], x.0[2]))
+        .collect();
+    let (left, top, right, bottom) = if options.exclude_borders {
+        find_content_area(&all_pixels, width, height)
+    } else {
+        (0, 0, width, height)
+    };
+
+    let mut pixels = Vec::new();
+    let mut weights = Vec::new();
+    let mut cells = Vec::new();
+    for y in top..bottom {
+        for x in left..right {
+            if options.ignore_transparent && img.get_pixel(x, y).0[3] < TRANSPARENT_ALPHA {
+                continue;
+            }
+            pixels.push(all_pixels[(y * width + x) as usize]);
+            let dx = ((x - left) as f32 + 0.5) / (right - left) as f32 * 2.0 - 1.0;
+            let dy = ((y - top) as f32 + 0.5) / (bottom - top) as f32 * 2.0 - 1.0;
+            weights.push(match options.weighting {
+                Weighting::Center => center_weight(dx, dy),
+                _ => 1.0,
+            });
+            let cell_x = (x - left) as usize * LAYOUT_SIZE / (right - left) as usize;
+            let cell_y = (y - top) as usize * LAYOUT_SIZE / (bottom - top) as usize;
+            cells.push(cell_y * LAYOUT_SIZE + cell_x);
+        }
+    }
+
+    if options.weighting == Weighting::Saliency {
+        weights = saliency_weights(&pixels);
+    }
+
+    WeightedPixels {
+        pixels,
+        weights,
+        cells,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgba;
+
+    fn with_border(width: u32, height: u32, border: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            if x < border || y < border || x >= width - border || y >= height - border {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([(x * 37 % 256) as u8, (y * 91 % 256) as u8, 200, 255])
+            }
+        })
+    }
+
+    #[quickcheck]
+    fn find_content_area_removes_uniform_borders(size: u8, border: u8) -> bool {
+        let size = 8 + size as u32 % 56;
+        let border = border as u32 % (size / 4 + 1);
+        let img = with_border(size, size, border);
+        let pixels: Vec<Lab> = img
+            .pixels()
+            .map(|x| rgb8_to_lab(x.0[0], x.0[1], x.0[2]))
+            .collect();
+        find_content_area(&pixels, size, size) == (border, border, size - border, size - border)
+    }
+
+    #[quickcheck]
+    fn find_content_area_is_never_empty(width: u8, height: u8, r: u8, g: u8, b: u8) -> bool {
+        let (width, height) = (1 + width as u32 % 64, 1 + height as u32 % 64);
+        let pixels = vec![rgb8_to_lab(r, g, b); (width * height) as usize];
+        let (left, top, right, bottom) = find_content_area(&pixels, width, height);
+        left < right && top < bottom
+    }
+
+    #[quickcheck]
+    fn sample_pixels_ignores_transparent_pixels(width: u8, height: u8, alpha: u8) -> bool {
+        let (width, height) = (1 + width as u32 % 32, 1 + height as u32 % 32);
+        let img = RgbaImage::from_pixel(width, height, Rgba([10, 20, 30, alpha]));
+        let options = SamplingOptions {
+            exclude_borders: false,
+            ..SamplingOptions::default()
+        };
+        let sample = sample_pixels(&img, &options);
+        let expected = if alpha < TRANSPARENT_ALPHA {
+            0
+        } else {
+            width * height
+        };
+        sample.pixels.len() == expected as usize && sample.weights.len() == expected as usize
+    }
+
+    #[quickcheck]
+    fn sample_pixels_weights_are_positive(width: u8, height: u8, weighting: u8) -> bool {
+        let (width, height) = (8 + width as u32 % 32, 8 + height as u32 % 32);
+        let options = SamplingOptions {
+            weighting: match weighting % 3 {
+                0 => Weighting::Uniform,
+                1 => Weighting::Center,
+                _ => Weighting::Saliency,
+            },
+            ..SamplingOptions::default()
+        };
+        let sample = sample_pixels(&with_border(width, height, 2), &options);
+        sample.weights.iter().all(|x| *x > 0.0)
+    }
+
+    #[quickcheck]
+    fn pick_frames_are_distinct_and_in_range(total: u8, n: u8) -> bool {
+        let (total, n) = (total as usize, 1 + n as usize % 16);
+        let frames = pick_frames(total, n);
+        frames.len() == total.min(n)
+            && frames.iter().all(|x| *x < total)
+            && frames.windows(2).all(|x| x[0] < x[1])
+    }
+
+    #[quickcheck]
+    fn sample_pixels_cells_cover_the_layout(width: u8, height: u8) -> bool {
+        let (width, height) = (8 + width as u32 % 32, 8 + height as u32 % 32);
+        let sample = sample_pixels(&with_border(width, height, 1), &SamplingOptions::default());
+        (0..LAYOUT_SIZE * LAYOUT_SIZE).all(|cell| sample.cells.contains(&cell))
+            && sample.cells.len() == sample.pixels.len()
+    }
+}
@@ -0,0 +1,509 @@
+use async_channel::{Receiver, Sender};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::actors::color_index::ColorIndexMessage;
+use crate::actors::dominant_color::DominantColorDistanceMessage;
+use crate::actors::dominant_color_cache::DominantColorCacheMessage;
+use crate::color_index::{get_candidate_words, IndexEntry, NearestQuery};
+use crate::color_names::{describe_palette, ColorDescription};
+use crate::colors::{target_distance, ImageColors, Target};
+use crate::filter::CandidateFilter;
+use crate::http::FetchEvent;
+use crate::image_source::{self, Candidate, ImageSource, Listing};
+use crate::loggable::Loggable;
+use crate::ranking::RankingWeights;
+
+#[derive(Debug, Error)]
+pub enum ErrorCode {
+    #[error("generic error")]
+    Error,
+    #[error("cannot send progress")]
+    CannotSendProgress,
+    #[error("cannot send to cache")]
+    CannotSendToCache,
+    #[error("cannot wait cache")]
+    CannotWaitCache,
+    #[error("cannot send to index")]
+    CannotSendToIndex,
+    #[error("cannot wait index")]
+    CannotWaitIndex,
+    #[error("{0}")]
+    Source(#[from] image_source::ErrorCode),
+}
+
+// Indexed matches farther than this from the target, in Lab units, are not
+// good enough to skip searching the source
+const MAX_INDEXED_DISTANCE: f32 = 10.0;
+
+// Actors every search talks to
+#[derive(Clone)]
+pub struct SearchActors {
+    pub cache: Sender<DominantColorCacheMessage>,
+    pub dominant_color: Sender<DominantColorDistanceMessage>,
+    pub index: Sender<ColorIndexMessage>,
+}
+
+pub struct SearchQuery {
+    pub q: String,
+    pub target: Target,
+    // skip the color index and rank the candidates of the source
+    pub fresh: bool,
+    pub weights: RankingWeights,
+    pub listing: Listing,
+    pub filter: CandidateFilter,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    images: Vec<SearchResultImage>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResultImage {
+    #[serde(flatten)]
+    candidate: Candidate,
+    colors: Vec<ColorDescription>,
+    // set when the image url redirects elsewhere
+    #[serde(skip_serializing_if = "Option::is_none")]
+    final_url: Option<String>,
+}
+
+impl SearchResultImage {
+    fn new(candidate: Candidate, colors: &ImageColors) -> SearchResultImage {
+        SearchResultImage {
+            final_url: colors.final_url.clone().filter(|x| *x != candidate.url),
+            candidate,
+            colors: describe_palette(&colors.palette),
+        }
+    }
+}
+
+// None when the image cannot be analyzed
+async fn get_distance(
+    cache_actor: &Sender<DominantColorCacheMessage>,
+    dist_actor: &Sender<DominantColorDistanceMessage>,
+    url: &String,
+    target: &Target,
+    progress: &Sender<String>,
+    v: f32,
+) -> Result<Option<(u32, ImageColors)>, ErrorCode> {
+    log::trace!("get_distance");
+    let (w, s) = oneshot::channel();
+    cache_actor
+        .send(DominantColorCacheMessage::Read(url.clone(), w))
+        .await
+        .or(Err(ErrorCode::CannotSendToCache))?;
+    let cached = s.await.or(Err(ErrorCode::CannotWaitCache))?;
+    // entries cached without a layout are analyzed again for layout targets
+    if let Some(dominant_colors) = cached.filter(|x| x.can_compare(target)) {
+        log::trace!("get_distance: 1.1");
+        let distance = target_distance(target, &dominant_colors) as u32;
+        return Ok(Some((distance, dominant_colors)));
+    }
+    log::trace!("get_distance: 2");
+    let (w, s) = oneshot::channel();
+    if let Err(_) = dist_actor
+        .send(DominantColorDistanceMessage(
+            url.clone(),
+            target.clone(),
+            w,
+            Box::new(send_fetch_progress(progress.clone(), v)),
+        ))
+        .await
+    {
+        return Ok(None);
+    }
+    log::trace!("get_distance: 3");
+    match s.await {
+        Err(_) => Ok(None),
+        Ok(None) => Ok(None),
+        Ok(Some((dominant_colors, distance))) => {
+            log::trace!("get_distance: 4");
+            cache_actor
+                .send(DominantColorCacheMessage::Write(
+                    url.clone(),
+                    dominant_colors.clone(),
+                ))
+                .await
+                .or(Err(ErrorCode::Error))?;
+            log::trace!("get_distance: 5");
+            Ok(Some((distance, dominant_colors)))
+        }
+    }
+}
+
+async fn get_indexed(
+    index_actor: &Sender<ColorIndexMessage>,
+    query: NearestQuery,
+) -> Result<Vec<(f32, IndexEntry)>, ErrorCode> {
+    let (w, s) = oneshot::channel();
+    index_actor
+        .send(ColorIndexMessage::Nearest(query, w))
+        .await
+        .or(Err(ErrorCode::CannotSendToIndex))?;
+    s.await.or(Err(ErrorCode::CannotWaitIndex))
+}
+
+async fn run_and_log(search: impl std::future::Future<Output = Result<SearchResult, ErrorCode>>) {
+    let _ = search.await.log_if_error();
+}
+
+pub fn search_with_progress(
+    source: Arc<dyn ImageSource>,
+    query: SearchQuery,
+    actors: SearchActors,
+) -> Receiver<String> {
+    let (progress, r) = async_channel::unbounded::<String>();
+    tokio::spawn(run_and_log(async move {
+        search(source.as_ref(), &query, &actors, progress).await
+    }));
+    r
+}
+
+pub async fn get_search_result(
+    source: Arc<dyn ImageSource>,
+    query: SearchQuery,
+    actors: SearchActors,
+) -> Result<SearchResult, ErrorCode> {
+    // nobody listens to the progress, but the receiver must stay alive
+    // until the search finishes or sending progress fails
+    let (progress, _r) = async_channel::unbounded::<String>();
+    search(source.as_ref(), &query, &actors, progress)
+        .await
+        .log_if_error()
+}
+
+async fn send_progress(
+    progress: &Sender<String>,
+    v: f32,
+    msg: Option<&str>,
+) -> Result<(), ErrorCode> {
+    // log::trace!("start {} {:?}", v, msg);
+    let str = match msg {
+        None => format!("{{\"v\":{}}}", v),
+        Some(msg) => format!("{{\"v\":{},\"msg\":\"{}\"}}", v, msg),
+    };
+    progress
+        .send(str)
+        .await
+        .or(Err(ErrorCode::CannotSendProgress))?;
+    Ok(())
+}
+
+// Retries and rate limit waits happen inside the http client, which cannot
+// wait for the progress channel, but it is unbounded so try_send never blocks
+fn send_fetch_progress(progress: Sender<String>, v: f32) -> impl FnMut(&FetchEvent) + Send {
+    move |event| {
+        let msg = match event {
+            FetchEvent::Retry(retry) => format!(
+                "retrying {} in {}ms, attempt {} failed: {}",
+                retry.url, retry.delay_ms, retry.attempt, retry.reason
+            ),
+            FetchEvent::RateLimited { wait_ms, .. } => {
+                format!("waiting {}ms for the reddit rate limit", wait_ms)
+            }
+        };
+        let progress_json = serde_json::json!({ "v": v, "msg": msg, "event": event });
+        let _ = progress.try_send(progress_json.to_string());
+    }
+}
+
+async fn send_progress_result<T: Serialize>(
+    progress: &Sender<String>,
+    obj: T,
+) -> Result<T, ErrorCode> {
+    let r_json = serde_json::to_string(&obj).or(Err(ErrorCode::Error))?;
+    progress.send(r_json).await.or(Err(ErrorCode::Error))?;
+    Ok(obj)
+}
+
+// Answers from the color index when it already has enough matches of the
+// query. Otherwise, or when fresh is set or the listing is not the default,
+// ranks the candidates of the source too, reading their colors from the cache
+// or analyzing them, and adds them to the index. Results are ordered by their
+// color distance to the target and the relevance of their text to the query.
+async fn search(
+    source: &dyn ImageSource,
+    query: &SearchQuery,
+    actors: &SearchActors,
+    progress: Sender<String>,
+) -> Result<SearchResult, ErrorCode> {
+    let (q, target) = (query.q.as_str(), &query.target);
+    log::info!("searching {} for {}", source.name(), q);
+    send_progress(&progress, 0.0, None).await?;
+
+    let return_qtd = 3;
+    let total = return_qtd * 100;
+    let mut cursor: Option<String> = None;
+
+    let mut candidates: Vec<(Option<f32>, Vec<String>, SearchResultImage)> = vec![];
+    let mut indexed = HashSet::new();
+    let mut close = 0;
+
+    // the index knows the candidates of the default listing only
+    let indexable = !query.fresh && query.listing == Listing::default();
+    if indexable {
+        let nearest = NearestQuery {
+            source: source.name().to_owned(),
+            query: q.to_owned(),
+            target: target.clone(),
+            limit: return_qtd,
+            weights: query.weights,
+            filter: query.filter.clone(),
+        };
+        for (distance, entry) in get_indexed(&actors.index, nearest).await? {
+            indexed.insert(entry.candidate.id.clone());
+            if distance <= MAX_INDEXED_DISTANCE {
+                close += 1;
+            }
+            let words = get_candidate_words(&entry.candidate);
+            let image = SearchResultImage::new(entry.candidate.clone(), &entry.colors());
+            candidates.push((Some(distance), words, image));
+        }
+        log::debug!("{} indexed matches", candidates.len());
+    }
+
+    // the index answers alone when it has enough close matches
+    let mut live = !indexable || close < return_qtd;
+    let source_query = source.narrow_query(q, &query.filter);
+    let mut currenti = 0.0f32;
+    while live {
+        let on_event = send_fetch_progress(progress.clone(), currenti / total as f32);
+        let page = source
+            .next_page(&source_query, &query.listing, cursor, Box::new(on_event))
+            .await?;
+        for candidate in page.candidates {
+            // filtered candidates are never downloaded
+            if indexed.contains(&candidate.id) || !query.filter.matches(&candidate) {
+                continue;
+            }
+            currenti += 1.0;
+            send_progress(&progress, currenti / total as f32, Some(&candidate.url)).await?;
+            let distance = get_distance(
+                &actors.cache,
+                &actors.dominant_color,
+                &candidate.url,
+                target,
+                &progress,
+                currenti / total as f32,
+            )
+            .await?;
+            let words = get_candidate_words(&candidate);
+            let (distance, image) = match distance {
+                None => (
+                    None,
+                    SearchResultImage::new(candidate, &ImageColors::default()),
+                ),
+                Some((distance, colors)) => {
+                    let entry = IndexEntry::new(source.name(), candidate.clone(), &colors);
+                    actors
+                        .index
+                        .send(ColorIndexMessage::Insert(entry))
+                        .await
+                        .or(Err(ErrorCode::CannotSendToIndex))?;
+                    (
+                        Some(distance as f32),
+                        SearchResultImage::new(candidate, &colors),
+                    )
+                }
+            };
+            candidates.push((distance, words, image));
+
+            if candidates.len() >= total {
+                break;
+            }
+        }
+
+        cursor = page.next;
+        live = candidates.len() < total && cursor.is_some();
+    }
+
+    let images: Vec<SearchResultImage> = query
+        .weights
+        .rank(q, candidates, |x| (x.0, x.1.clone()))
+        .into_iter()
+        .take(return_qtd)
+        .map(|x| x.2)
+        .collect();
+
+    send_progress(&progress, 1.0, None).await?;
+    let result = send_progress_result(&progress, SearchResult { images }).await?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::actors::color_index::spawn_color_index;
+    use crate::color_index::ColorIndex;
+    use crate::colors::{Palette, PaletteColor};
+    use crate::http::OnFetchEvent;
+    use crate::image_source::Page;
+    use async_trait::async_trait;
+    use palette::Lab;
+    use serde_json::Map;
+
+    // Pages of one candidate, whose url and score are its lightness
+    struct LightnessSource(Vec<u8>);
+
+    #[async_trait]
+    impl ImageSource for LightnessSource {
+        fn name(&self) -> &str {
+            "lightness"
+        }
+
+        async fn next_page(
+            &self,
+            _: &str,
+            _: &Listing,
+            cursor: Option<String>,
+            _: OnFetchEvent,
+        ) -> Result<Page, image_source::ErrorCode> {
+            let i = cursor.map_or(0, |x| x.parse::<usize>().unwrap());
+            let mut metadata = Map::new();
+            metadata.insert("title".to_owned(), "gray".into());
+            metadata.insert("score".to_owned(), self.0[i].into());
+            Ok(Page {
+                candidates: vec![Candidate {
+                    id: i.to_string(),
+                    url: self.0[i].to_string(),
+                    metadata,
+                }],
+                next: Some(i + 1)
+                    .filter(|x| *x < self.0.len())
+                    .map(|x| x.to_string()),
+            })
+        }
+    }
+
+    fn gray(l: f32) -> Palette {
+        vec![PaletteColor {
+            color: Lab::new(l, 0.0, 0.0),
+            weight: 1.0,
+        }]
+    }
+
+    // Cache that knows every image, so nothing is downloaded
+    fn spawn_lightness_cache() -> Sender<DominantColorCacheMessage> {
+        let (w, r) = async_channel::unbounded::<DominantColorCacheMessage>();
+        tokio::spawn(async move {
+            while let Ok(msg) = r.recv().await {
+                if let DominantColorCacheMessage::Read(url, reply) = msg {
+                    let _ = reply.send(Some(ImageColors {
+                        palette: gray(url.parse().unwrap()),
+                        layout: vec![],
+                        final_url: None,
+                    }));
+                }
+            }
+        });
+        w
+    }
+
+    async fn search_lightness(
+        source: &dyn ImageSource,
+        fresh: bool,
+        filter: CandidateFilter,
+        index_actor: Sender<ColorIndexMessage>,
+    ) -> Vec<String> {
+        let (dominant_color, _r) = async_channel::unbounded();
+        let (progress, _progress) = async_channel::unbounded();
+        let actors = SearchActors {
+            cache: spawn_lightness_cache(),
+            dominant_color,
+            index: index_actor,
+        };
+        let query = SearchQuery {
+            q: "gray".to_owned(),
+            target: Target::Palette(gray(50.0)),
+            fresh,
+            weights: RankingWeights::default(),
+            listing: Listing::default(),
+            filter,
+        };
+        let result = search(source, &query, &actors, progress).await.unwrap();
+        result.images.into_iter().map(|x| x.candidate.url).collect()
+    }
+
+    #[tokio::test]
+    async fn search_ranks_every_page_of_the_source() {
+        let source = LightnessSource(vec![90, 10, 55, 40, 70, 5]);
+        let urls = search_lightness(
+            &source,
+            false,
+            CandidateFilter::default(),
+            spawn_color_index(ColorIndex::new()),
+        )
+        .await;
+        assert_eq!(urls, vec!["55", "40", "70"]);
+    }
+
+    #[tokio::test]
+    async fn search_answers_from_the_index_unless_fresh() {
+        let index_actor = spawn_color_index(ColorIndex::new());
+        let source = LightnessSource(vec![90, 10, 54, 43, 48, 5]);
+        search_lightness(
+            &source,
+            false,
+            CandidateFilter::default(),
+            index_actor.clone(),
+        )
+        .await;
+
+        // the source now has better images, but the index is close enough
+        let source = LightnessSource(vec![50, 52, 49, 47]);
+        let urls = search_lightness(
+            &source,
+            false,
+            CandidateFilter::default(),
+            index_actor.clone(),
+        )
+        .await;
+        assert_eq!(urls, vec!["48", "54", "43"]);
+        let urls = search_lightness(
+            &source,
+            true,
+            CandidateFilter::default(),
+            index_actor.clone(),
+        )
+        .await;
+        assert_eq!(urls, vec!["50", "49", "52"]);
+    }
+
+    #[tokio::test]
+    async fn search_asks_the_source_when_the_index_is_too_far() {
+        let index_actor = spawn_color_index(ColorIndex::new());
+        let source = LightnessSource(vec![90, 10, 55, 40, 70, 5]);
+        search_lightness(
+            &source,
+            false,
+            CandidateFilter::default(),
+            index_actor.clone(),
+        )
+        .await;
+
+        // 70 is too far from the target, so the source is searched again
+        let source = LightnessSource(vec![62, 52]);
+        let urls = search_lightness(&source, false, CandidateFilter::default(), index_actor).await;
+        assert_eq!(urls, vec!["52", "55", "40"]);
+    }
+
+    #[tokio::test]
+    async fn search_skips_filtered_candidates() {
+        let index_actor = spawn_color_index(ColorIndex::new());
+        let source = LightnessSource(vec![90, 10, 55, 40, 70, 5]);
+        let popular = |min_score| CandidateFilter {
+            min_score: Some(min_score),
+            ..CandidateFilter::default()
+        };
+        let urls = search_lightness(&source, true, popular(50), index_actor.clone()).await;
+        assert_eq!(urls, vec!["55", "70", "90"]);
+
+        // the index has two matches only, so the source is searched again
+        let urls = search_lightness(&source, false, popular(60), index_actor).await;
+        assert_eq!(urls, vec!["70", "90"]);
+    }
+}
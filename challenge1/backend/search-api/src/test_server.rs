@@ -0,0 +1,111 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub struct TestRequest {
+    pub method: String,
+    pub path: String,
+    // header lines as sent, names lowercased
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl TestRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn find_header_end(data: &[u8]) -> Option<usize> {
+    data.windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .map(|x| x + 4)
+}
+
+async fn read_request(socket: &mut TcpStream) -> Option<TestRequest> {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let header_end = loop {
+        let n = socket.read(&mut buffer).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..n]);
+        if let Some(end) = find_header_end(&data) {
+            break end;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let path = request_line.next().unwrap_or("/").to_owned();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|x| {
+            let colon = x.find(':')?;
+            Some((x[..colon].to_lowercase(), x[colon + 1..].trim().to_owned()))
+        })
+        .collect();
+
+    let length = headers
+        .iter()
+        .find(|(x, _)| x == "content-length")
+        .and_then(|(_, x)| x.parse::<usize>().ok())
+        .unwrap_or(0);
+    while data.len() < header_end + length {
+        let n = socket.read(&mut buffer).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..n]);
+    }
+    let body = String::from_utf8_lossy(&data[header_end..]).into_owned();
+
+    Some(TestRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+// Local server answering each request with the response for it, returns
+// its base url
+pub async fn serve(respond: fn(&TestRequest) -> String) -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            if let Some(request) = read_request(&mut socket).await {
+                let _ = socket.write_all(respond(&request).as_bytes()).await;
+            }
+        }
+    });
+    format!("http://{}", address)
+}
+
+pub fn redirect(location: &str) -> String {
+    format!(
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location
+    )
+}
+
+pub fn ok(body: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+pub fn status(code: u16) -> String {
+    format!(
+        "HTTP/1.1 {} Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        code
+    )
+}
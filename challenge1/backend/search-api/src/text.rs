@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+// Lowercase words, split on anything that is not a letter or a digit
+pub fn get_words(s: &str) -> Vec<String> {
+    s.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_owned())
+        .collect()
+}
+
+// Every word of the query must be part of a tag, an empty query matches
+// everything
+pub fn matches_query(query: &[String], tags: &[String]) -> bool {
+    query
+        .iter()
+        .all(|word| tags.iter().any(|x| x.contains(word)))
+}
+
+// Okapi BM25 parameters
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+// Inverted index of documents made of words, identified by the order they
+// were added in
+#[derive(Debug, Default)]
+pub struct TextIndex {
+    // documents of each word, with how many times the word appears in them
+    postings: HashMap<String, Vec<(usize, u32)>>,
+    lengths: Vec<usize>,
+    total_length: usize,
+}
+
+impl TextIndex {
+    pub fn new() -> TextIndex {
+        TextIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    pub fn add(&mut self, words: &[String]) -> usize {
+        let document = self.lengths.len();
+        let mut frequencies: HashMap<&String, u32> = HashMap::new();
+        for word in words {
+            *frequencies.entry(word).or_insert(0) += 1;
+        }
+        for (word, frequency) in frequencies {
+            self.postings
+                .entry(word.clone())
+                .or_insert_with(Vec::new)
+                .push((document, frequency));
+        }
+        self.lengths.push(words.len());
+        self.total_length += words.len();
+        document
+    }
+
+    // BM25 relevance of every document with at least one word of the query.
+    // Rare words count more than common ones, and matches in short titles
+    // more than in long ones.
+    pub fn scores(&self, query: &[String]) -> HashMap<usize, f32> {
+        let mut scores = HashMap::new();
+        if self.is_empty() {
+            return scores;
+        }
+        let n = self.len() as f32;
+        let average_length = (self.total_length as f32 / n).max(1.0);
+        let mut words: Vec<&String> = query.iter().collect();
+        words.sort();
+        words.dedup();
+        for word in words {
+            let postings = match self.postings.get(word) {
+                Some(postings) => postings,
+                None => continue,
+            };
+            let matches = postings.len() as f32;
+            let idf = ((n - matches + 0.5) / (matches + 0.5) + 1.0).ln();
+            for (document, frequency) in postings {
+                let frequency = *frequency as f32;
+                let length = self.lengths[*document] as f32 / average_length;
+                let score =
+                    idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length));
+                *scores.entry(*document).or_insert(0.0) += score;
+            }
+        }
+        scores
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words(s: &[&str]) -> Vec<Vec<String>> {
+        s.iter().map(|x| get_words(x)).collect()
+    }
+
+    #[test]
+    fn rare_words_and_short_documents_score_more() {
+        let mut index = TextIndex::new();
+        for document in words(&[
+            "Red Ferrari",
+            "Red Ferrari at the track, with red sunset and red lights",
+            "Red sunset",
+            "Blue sea",
+        ]) {
+            index.add(&document);
+        }
+        let scores = index.scores(&get_words("ferrari"));
+        assert_eq!(scores.len(), 2);
+        assert!(scores[&0] > scores[&1]);
+        let scores = index.scores(&get_words("red sunset"));
+        assert!(scores[&2] > scores[&0]);
+        assert!(!scores.contains_key(&3));
+        assert!(index.scores(&get_words("green")).is_empty());
+    }
+
+    #[quickcheck]
+    fn scores_are_positive(documents: Vec<String>, query: String) -> bool {
+        let mut index = TextIndex::new();
+        for document in documents.iter() {
+            index.add(&get_words(document));
+        }
+        index.scores(&get_words(&query)).values().all(|x| *x > 0.0)
+    }
+}
@@ -0,0 +1,62 @@
+// RFC 3986 unreserved characters, never encoded
+pub fn is_unreserved(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_' || c == b'~'
+}
+
+// Encodes every byte of the utf-8 representation that keep rejects
+pub fn percent_encode(s: &str, keep: fn(u8) -> bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for c in s.bytes() {
+        if keep(c) {
+            encoded.push(c as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", c));
+        }
+    }
+    encoded
+}
+
+// None when an escape is truncated or the result is not utf-8
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[quickcheck]
+    fn percent_decode_reverts_percent_encode(s: String) -> bool {
+        let encoded = percent_encode(&s, is_unreserved);
+        encoded.bytes().all(|c| is_unreserved(c) || c == b'%')
+            && percent_decode(&encoded) == Some(s)
+    }
+
+    #[test]
+    fn percent_encode_uses_utf8_bytes() {
+        assert_eq!(
+            percent_encode("café au lait", is_unreserved),
+            "caf%C3%A9%20au%20lait"
+        );
+        assert_eq!(percent_decode("caf%c3%a9"), Some("café".to_owned()));
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+    }
+}